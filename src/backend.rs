@@ -0,0 +1,464 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::convert::Infallible;
+
+use crate::{Rng, TemplateSource, UpdatedValues};
+
+// Write side of a template store, on top of the read-only `TemplateSource`. Every backend
+// follows the SQLite semantics: names compare ASCII case-insensitively, inserting into a
+// missing template creates it, and operations on missing templates or renames onto an
+// existing name change nothing instead of failing.
+pub trait StorageBackend: TemplateSource {
+    fn insert_subs<'a>(
+        &mut self,
+        template: &str,
+        substitutes: &[&'a str],
+    ) -> Result<UpdatedValues<'a>, Self::Error>;
+
+    fn remove_subs<'a>(
+        &mut self,
+        template: &str,
+        substitutes: &[&'a str],
+    ) -> Result<UpdatedValues<'a>, Self::Error>;
+
+    fn remove_template(&mut self, template: &str) -> Result<bool, Self::Error>;
+
+    fn rename_template(
+        &mut self,
+        old_template: &str,
+        new_template: &str,
+    ) -> Result<bool, Self::Error>;
+
+    fn rename_sub(
+        &mut self,
+        template: &str,
+        old_sub: &str,
+        new_sub: &str,
+    ) -> Result<bool, Self::Error>;
+
+    fn clear(&mut self) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug, Clone, Default)]
+struct MemoryTemplate {
+    name: String,
+    subs: Vec<String>,
+}
+
+impl MemoryTemplate {
+    fn position(&self, substitute: &str) -> Option<usize> {
+        self.subs
+            .iter()
+            .position(|x| x.eq_ignore_ascii_case(substitute))
+    }
+}
+
+// Backend that keeps everything in process memory, mainly as a test double.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    templates: BTreeMap<String, MemoryTemplate>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+}
+
+fn key(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+impl TemplateSource for MemoryBackend {
+    type Error = Infallible;
+
+    fn templates(&self) -> Result<Vec<String>, Infallible> {
+        Ok(self.templates.values().map(|x| x.name.clone()).collect())
+    }
+
+    fn subs(&self, template: &str) -> Result<Option<Vec<String>>, Infallible> {
+        Ok(self.templates.get(&key(template)).map(|template| {
+            let mut subs = template.subs.clone();
+            subs.sort_by_key(|x| x.to_ascii_lowercase());
+            subs
+        }))
+    }
+
+    fn random_sub(&self, template: &str, rng: &mut Rng) -> Result<Option<String>, Infallible> {
+        Ok(self.templates.get(&key(template)).map(|template| {
+            if template.subs.is_empty() {
+                return String::new();
+            }
+            template.subs[rng.below(template.subs.len())].clone()
+        }))
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn insert_subs<'a>(
+        &mut self,
+        template: &str,
+        substitutes: &[&'a str],
+    ) -> Result<UpdatedValues<'a>, Infallible> {
+        let entry = self
+            .templates
+            .entry(key(template))
+            .or_insert_with(|| MemoryTemplate {
+                name: template.to_string(),
+                subs: Vec::new(),
+            });
+
+        let mut inserted_subs = UpdatedValues::new();
+        for sub in substitutes {
+            if entry.position(sub).is_none() {
+                entry.subs.push(sub.to_string());
+                inserted_subs.push(*sub);
+            }
+        }
+        Ok(inserted_subs)
+    }
+
+    fn remove_subs<'a>(
+        &mut self,
+        template: &str,
+        substitutes: &[&'a str],
+    ) -> Result<UpdatedValues<'a>, Infallible> {
+        let mut removed_subs = UpdatedValues::new();
+        if let Some(entry) = self.templates.get_mut(&key(template)) {
+            for sub in substitutes {
+                if let Some(position) = entry.position(sub) {
+                    entry.subs.remove(position);
+                    removed_subs.push(*sub);
+                }
+            }
+        }
+        Ok(removed_subs)
+    }
+
+    fn remove_template(&mut self, template: &str) -> Result<bool, Infallible> {
+        Ok(self.templates.remove(&key(template)).is_some())
+    }
+
+    fn rename_template(
+        &mut self,
+        old_template: &str,
+        new_template: &str,
+    ) -> Result<bool, Infallible> {
+        let (old_key, new_key) = (key(old_template), key(new_template));
+        if old_key != new_key && self.templates.contains_key(&new_key) {
+            return Ok(false);
+        }
+        match self.templates.remove(&old_key) {
+            Some(mut entry) => {
+                entry.name = new_template.to_string();
+                self.templates.insert(new_key, entry);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn rename_sub(
+        &mut self,
+        template: &str,
+        old_sub: &str,
+        new_sub: &str,
+    ) -> Result<bool, Infallible> {
+        let Some(entry) = self.templates.get_mut(&key(template)) else {
+            return Ok(false);
+        };
+        let Some(position) = entry.position(old_sub) else {
+            return Ok(false);
+        };
+        if entry.position(new_sub).is_some_and(|x| x != position) {
+            return Ok(false);
+        }
+        entry.subs[position] = new_sub.to_string();
+        Ok(true)
+    }
+
+    fn clear(&mut self) -> Result<(), Infallible> {
+        self.templates.clear();
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct CachedSubs {
+    subs: Vec<String>,
+    // Enabled substitutes and their weights, as given by `weighted_subs`.
+    enabled: Vec<String>,
+    weights: Vec<i64>,
+}
+
+#[derive(Debug, Default)]
+struct LruCache {
+    tick: u64,
+    entries: BTreeMap<String, (u64, Option<CachedSubs>)>,
+}
+
+// Decorator that keeps the substitutes of the most recently used templates in memory, so
+// repeated random picks stop hitting the inner backend. Picks follow the weights the inner
+// backend reports, as of when the template was cached, and are passed to its `record_pick`.
+// Writes made through the decorator invalidate the affected templates; writes made to the
+// underlying store by anyone else are only seen after `invalidate` or `invalidate_all`.
+#[derive(Debug)]
+pub struct CachedBackend<B> {
+    inner: B,
+    capacity: usize,
+    cache: RefCell<LruCache>,
+}
+
+impl<B: StorageBackend> CachedBackend<B> {
+    pub fn new(inner: B, capacity: usize) -> CachedBackend<B> {
+        CachedBackend {
+            inner,
+            capacity: capacity.max(1),
+            cache: RefCell::new(LruCache::default()),
+        }
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+
+    pub fn invalidate(&self, template: &str) {
+        self.cache.borrow_mut().entries.remove(&key(template));
+    }
+
+    pub fn invalidate_all(&self) {
+        self.cache.borrow_mut().entries.clear();
+    }
+
+    fn with_cached<T>(
+        &self,
+        template: &str,
+        f: impl FnOnce(Option<&CachedSubs>) -> T,
+    ) -> Result<T, B::Error> {
+        let key = key(template);
+        let mut cache = self.cache.borrow_mut();
+        cache.tick += 1;
+        let tick = cache.tick;
+
+        if let Some(entry) = cache.entries.get_mut(&key) {
+            entry.0 = tick;
+            return Ok(f(entry.1.as_ref()));
+        }
+
+        let subs = match self.inner.subs(template)? {
+            Some(subs) => {
+                let (enabled, weights) = self
+                    .inner
+                    .weighted_subs(template)?
+                    .unwrap_or_default()
+                    .into_iter()
+                    .unzip();
+                Some(CachedSubs {
+                    subs,
+                    enabled,
+                    weights,
+                })
+            }
+            None => None,
+        };
+
+        if cache.entries.len() >= self.capacity {
+            let oldest = cache
+                .entries
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                cache.entries.remove(&oldest);
+            }
+        }
+
+        let result = f(subs.as_ref());
+        cache.entries.insert(key, (tick, subs));
+        Ok(result)
+    }
+}
+
+impl<B: StorageBackend> TemplateSource for CachedBackend<B> {
+    type Error = B::Error;
+
+    fn templates(&self) -> Result<Vec<String>, B::Error> {
+        self.inner.templates()
+    }
+
+    fn subs(&self, template: &str) -> Result<Option<Vec<String>>, B::Error> {
+        self.with_cached(template, |cached| cached.map(|x| x.subs.clone()))
+    }
+
+    fn random_sub(&self, template: &str, rng: &mut Rng) -> Result<Option<String>, B::Error> {
+        let picked = self.with_cached(template, |cached| {
+            cached.map(|cached| {
+                rng.weighted_index(&cached.weights)
+                    .map(|index| cached.enabled[index].clone())
+            })
+        })?;
+        match picked {
+            Some(Some(sub)) => {
+                self.inner.record_pick(template, &sub)?;
+                Ok(Some(sub))
+            }
+            Some(None) => Ok(Some(String::new())),
+            None => Ok(None),
+        }
+    }
+
+    fn weighted_subs(&self, template: &str) -> Result<Option<Vec<(String, i64)>>, B::Error> {
+        self.with_cached(template, |cached| {
+            cached.map(|cached| {
+                cached
+                    .enabled
+                    .iter()
+                    .cloned()
+                    .zip(cached.weights.iter().copied())
+                    .collect()
+            })
+        })
+    }
+
+    fn record_pick(&self, template: &str, substitute: &str) -> Result<(), B::Error> {
+        self.inner.record_pick(template, substitute)
+    }
+}
+
+impl<B: StorageBackend> StorageBackend for CachedBackend<B> {
+    fn insert_subs<'a>(
+        &mut self,
+        template: &str,
+        substitutes: &[&'a str],
+    ) -> Result<UpdatedValues<'a>, B::Error> {
+        self.invalidate(template);
+        self.inner.insert_subs(template, substitutes)
+    }
+
+    fn remove_subs<'a>(
+        &mut self,
+        template: &str,
+        substitutes: &[&'a str],
+    ) -> Result<UpdatedValues<'a>, B::Error> {
+        self.invalidate(template);
+        self.inner.remove_subs(template, substitutes)
+    }
+
+    fn remove_template(&mut self, template: &str) -> Result<bool, B::Error> {
+        self.invalidate(template);
+        self.inner.remove_template(template)
+    }
+
+    fn rename_template(
+        &mut self,
+        old_template: &str,
+        new_template: &str,
+    ) -> Result<bool, B::Error> {
+        self.invalidate(old_template);
+        self.invalidate(new_template);
+        self.inner.rename_template(old_template, new_template)
+    }
+
+    fn rename_sub(
+        &mut self,
+        template: &str,
+        old_sub: &str,
+        new_sub: &str,
+    ) -> Result<bool, B::Error> {
+        self.invalidate(template);
+        self.inner.rename_sub(template, old_sub, new_sub)
+    }
+
+    fn clear(&mut self) -> Result<(), B::Error> {
+        self.invalidate_all();
+        self.inner.clear()
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl StorageBackend for crate::TemplateDatabase {
+    fn insert_subs<'a>(
+        &mut self,
+        template: &str,
+        substitutes: &[&'a str],
+    ) -> crate::Result<UpdatedValues<'a>> {
+        crate::error::with_context("insert_subs", Some(template), None, || {
+            let tx = self.db.savepoint()?;
+            Self::execute_check_unlocked(&tx, template)?;
+            Self::execute_insert_template(&tx, template)?;
+            let inserted_subs = Self::execute_insert_subs(&tx, template, substitutes)?;
+            tx.commit()?;
+            Ok(inserted_subs)
+        })
+    }
+
+    fn remove_subs<'a>(
+        &mut self,
+        template: &str,
+        substitutes: &[&'a str],
+    ) -> crate::Result<UpdatedValues<'a>> {
+        crate::error::with_context("remove_subs", Some(template), None, || {
+            if self.find_template(template)?.is_none() {
+                return Ok(UpdatedValues::new());
+            }
+            crate::TemplateDatabase::remove_subs(self, template, substitutes)
+        })
+    }
+
+    fn remove_template(&mut self, template: &str) -> crate::Result<bool> {
+        crate::error::with_context("remove_template", Some(template), None, || {
+            if self.find_template(template)?.is_none() {
+                return Ok(false);
+            }
+            crate::TemplateDatabase::remove_template(self, template)
+        })
+    }
+
+    fn rename_template(&mut self, old_template: &str, new_template: &str) -> crate::Result<bool> {
+        crate::error::with_context(
+            "rename_template",
+            Some(old_template),
+            Some(new_template),
+            || {
+                let Some((old_id, _)) = self.find_template(old_template)? else {
+                    return Ok(false);
+                };
+                if let Some((new_id, _)) = self.find_template(new_template)? {
+                    if new_id != old_id {
+                        return Ok(false);
+                    }
+                }
+                crate::TemplateDatabase::rename_template(self, old_template, new_template)
+            },
+        )
+    }
+
+    fn rename_sub(&mut self, template: &str, old_sub: &str, new_sub: &str) -> crate::Result<bool> {
+        crate::error::with_context("rename_substitute", Some(template), Some(old_sub), || {
+            let Some((template_id, _)) = self.find_template(template)? else {
+                return Ok(false);
+            };
+            let ids: Vec<i64> = {
+                let mut stmt = self.db.prepare_cached(
+                    "SELECT id FROM substitutes WHERE template_id = ?1 AND name IN (?2, ?3)",
+                )?;
+                let ids = stmt.query_map((template_id, old_sub, new_sub), |row| row.get(0))?;
+                ids.collect::<rusqlite::Result<_>>()?
+            };
+            if ids.len() > 1 {
+                return Ok(false);
+            }
+            self.rename_substitute(template, old_sub, new_sub)
+        })
+    }
+
+    fn clear(&mut self) -> crate::Result<()> {
+        crate::TemplateDatabase::clear(self)
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+use crate::rng::Rng;
+use crate::weights::WeightedSubs;
+use crate::TemplateDatabase;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ChangeStamp {
+    data_version: i64,
+    total_changes: u64,
+}
+
+impl ChangeStamp {
+    // Accounts for writes made through this connection since the stamp was taken, while
+    // commits from other connections still make it stale.
+    pub(crate) fn with_own_changes(self, db: &TemplateDatabase) -> ChangeStamp {
+        ChangeStamp {
+            data_version: self.data_version,
+            total_changes: db.db.total_changes(),
+        }
+    }
+}
+
+impl TemplateDatabase {
+    // `data_version` moves when another connection commits, `total_changes` when this one
+    // writes, so together they tell whether anything read earlier may be out of date.
+    pub(crate) fn change_stamp(&self) -> rusqlite::Result<ChangeStamp> {
+        let data_version = self
+            .db
+            .query_row("PRAGMA data_version", [], |row| row.get(0))?;
+        Ok(ChangeStamp {
+            data_version,
+            total_changes: self.db.total_changes(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CachedTemplate {
+    id: i64,
+    name: String,
+    pinned: bool,
+    subs: Vec<String>,
+    enabled: WeightedSubs,
+}
+
+// In-memory copy of some or all templates. Lookups never touch SQLite; call `refresh` or
+// `refresh_if_stale` to pick up changes made to the database since the cache was loaded.
+// A refresh only reads the templates the cache serves, but staleness is tracked for the
+// database as a whole, so a write to any template makes the cache stale.
+// While usage tracking is on, random picks are counted in memory and written by
+// `record_usage` or the next refresh.
+#[derive(Debug, Clone)]
+pub struct TemplateCache {
+    selection: Option<Vec<String>>,
+    templates: HashMap<String, CachedTemplate>,
+    stamp: ChangeStamp,
+    rng: Rng,
+    track_usage: bool,
+    uses: HashMap<(i64, String), u64>,
+}
+
+fn cache_key(template: &str) -> String {
+    // Matches the NOCASE collation of template names.
+    template.to_ascii_lowercase()
+}
+
+impl TemplateCache {
+    pub fn load(db: &TemplateDatabase) -> rusqlite::Result<TemplateCache> {
+        Self::with_selection(db, None)
+    }
+
+    pub fn load_selected(
+        db: &TemplateDatabase,
+        templates: &[&str],
+    ) -> rusqlite::Result<TemplateCache> {
+        let selection = templates.iter().map(|x| x.to_string()).collect();
+        Self::with_selection(db, Some(selection))
+    }
+
+    fn with_selection(
+        db: &TemplateDatabase,
+        selection: Option<Vec<String>>,
+    ) -> rusqlite::Result<TemplateCache> {
+        let mut cache = TemplateCache {
+            selection,
+            templates: HashMap::new(),
+            stamp: db.change_stamp()?,
+            rng: Rng::from_db(&db.db)?,
+            track_usage: db.track_usage,
+            uses: HashMap::new(),
+        };
+        cache.refresh(db)?;
+        Ok(cache)
+    }
+
+    pub fn refresh(&mut self, db: &TemplateDatabase) -> rusqlite::Result<()> {
+        self.record_usage(db)?;
+
+        let tx = db.read_transaction()?;
+        let mut templates = HashMap::new();
+
+        {
+            let filter = match &self.selection {
+                Some(selection) => {
+                    let placeholders: Vec<String> =
+                        (1..=selection.len()).map(|x| format!("?{}", x)).collect();
+                    format!("WHERE templates.name IN ({})", placeholders.join(", "))
+                }
+                None => String::new(),
+            };
+            let mut stmt = tx.prepare(&format!(
+                "SELECT templates.id, templates.name, substitutes.name, substitutes.weight
+                 FROM templates
+                 LEFT JOIN substitutes ON substitutes.template_id = templates.id
+                 {}
+                 ORDER BY templates.id, LOWER(substitutes.name) ASC",
+                filter
+            ))?;
+            let selection = self.selection.as_deref().unwrap_or_default();
+            let mut rows = stmt.query(rusqlite::params_from_iter(selection))?;
+
+            while let Some(row) = rows.next()? {
+                let id: i64 = row.get(0)?;
+                let name: String = row.get(1)?;
+                let sub: Option<String> = row.get(2)?;
+                let weight: Option<i64> = row.get(3)?;
+
+                let entry = templates
+                    .entry(cache_key(&name))
+                    .or_insert_with(|| CachedTemplate {
+                        id,
+                        name,
+                        pinned: false,
+                        subs: Vec::new(),
+                        enabled: WeightedSubs::default(),
+                    });
+                if let (Some(sub), Some(weight)) = (sub, weight) {
+                    if weight > 0 {
+                        entry.enabled.subs.push(sub.clone());
+                        entry.enabled.weights.push(weight);
+                    }
+                    entry.subs.push(sub);
+                }
+            }
+        }
+
+        for template in templates.values_mut() {
+            if let Some(pinned) = db.pinned(&template.name) {
+                template.enabled = WeightedSubs::pinned(pinned);
+                template.pinned = true;
+            }
+        }
+
+        let stamp = db.change_stamp()?;
+        tx.commit()?;
+
+        self.templates = templates;
+        self.stamp = stamp;
+        self.track_usage = db.track_usage;
+        Ok(())
+    }
+
+    // Writes the picks counted since the last call, without making the cache stale.
+    pub fn record_usage(&mut self, db: &TemplateDatabase) -> rusqlite::Result<()> {
+        if self.uses.is_empty() {
+            return Ok(());
+        }
+
+        let fresh = !self.is_stale(db)?;
+        let tx = db.read_transaction()?;
+        for ((template_id, sub), count) in &self.uses {
+            TemplateDatabase::execute_record_use(&tx, template_id, sub, *count)?;
+        }
+        tx.commit()?;
+
+        self.uses.clear();
+        if fresh {
+            self.stamp = self.stamp.with_own_changes(db);
+        }
+        Ok(())
+    }
+
+    pub fn is_stale(&self, db: &TemplateDatabase) -> rusqlite::Result<bool> {
+        Ok(db.change_stamp()? != self.stamp)
+    }
+
+    pub fn refresh_if_stale(&mut self, db: &TemplateDatabase) -> rusqlite::Result<bool> {
+        if self.is_stale(db)? {
+            self.refresh(db)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    pub fn get_templates(&self) -> Vec<&str> {
+        let mut templates: Vec<&str> = self.templates.values().map(|x| x.name.as_str()).collect();
+        templates.sort_by_key(|x| x.to_lowercase());
+        templates
+    }
+
+    pub fn get_subs(&self, template: &str) -> Option<&[String]> {
+        self.templates
+            .get(&cache_key(template))
+            .map(|x| x.subs.as_slice())
+    }
+
+    pub fn get_random_sub(&mut self, template: &str) -> Option<&str> {
+        let cached = self.templates.get(&cache_key(template))?;
+        let Some(sub) = cached.enabled.pick(&mut self.rng) else {
+            return Some("");
+        };
+        if self.track_usage && !cached.pinned {
+            *self.uses.entry((cached.id, sub.to_string())).or_default() += 1;
+        }
+        Some(sub)
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+
+use crate::TemplateDatabase;
+
+// A substitute as shown after normalization, with the raw values that collapsed into it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanonicalSub {
+    // The earliest stored variant, kept with its original case.
+    pub value: String,
+    // Later variants that normalize to the same text, in insertion order.
+    pub collapsed: Vec<String>,
+}
+
+// Substitute names are only unique under ASCII case folding, so legacy data can still hold
+// values that differ in whitespace or non-ASCII case. Those compare equal here.
+pub(crate) fn normalize(value: &str) -> String {
+    value
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+impl TemplateDatabase {
+    // Like `get_subs`, but values that only differ after normalization are listed once.
+    pub fn get_subs_canonical(&self, template: &str) -> rusqlite::Result<Vec<CanonicalSub>> {
+        let template_id = self.find_template_id(template)?;
+        let mut stmt = self.db.prepare_cached(
+            "SELECT name
+             FROM substitutes
+             WHERE template_id = ?1
+             ORDER BY id ASC",
+        )?;
+
+        let substitutes = stmt.query_map([template_id], |row| row.get::<_, String>(0))?;
+
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        let mut canonical: Vec<CanonicalSub> = Vec::new();
+
+        for sub in substitutes {
+            let sub = sub?;
+            match positions.get(&normalize(&sub)) {
+                Some(index) => canonical[*index].collapsed.push(sub),
+                None => {
+                    positions.insert(normalize(&sub), canonical.len());
+                    canonical.push(CanonicalSub {
+                        value: sub,
+                        collapsed: Vec::new(),
+                    });
+                }
+            }
+        }
+
+        canonical.sort_by_key(|x| x.value.to_lowercase());
+
+        Ok(canonical)
+    }
+}
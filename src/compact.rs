@@ -0,0 +1,41 @@
+use rusqlite::Connection;
+
+use crate::{Result, TemplateDatabase};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompactReport {
+    // Database sizes in bytes, `page_count * page_size`.
+    pub size_before: u64,
+    pub size_after: u64,
+    // Pages released back to the file system.
+    pub reclaimed_pages: u64,
+    // Free pages found before compacting, the space deletions had left behind.
+    pub free_pages_before: u64,
+}
+
+fn pragma(db: &Connection, name: &str) -> rusqlite::Result<u64> {
+    let value: i64 = db.query_row(&format!("PRAGMA {}", name), [], |row| row.get(0))?;
+    Ok(value as u64)
+}
+
+impl TemplateDatabase {
+    // Rebuilds the database file with VACUUM so space freed by deletions is returned to the
+    // file system. Needs exclusive access for the duration and fails inside a batch.
+    pub fn compact(&mut self) -> Result<CompactReport> {
+        let page_size = pragma(&self.db, "page_size")?;
+        let pages_before = pragma(&self.db, "page_count")?;
+        let free_pages_before = pragma(&self.db, "freelist_count")?;
+
+        self.db.execute_batch("VACUUM;")?;
+
+        let page_size_after = pragma(&self.db, "page_size")?;
+        let pages_after = pragma(&self.db, "page_count")?;
+
+        Ok(CompactReport {
+            size_before: pages_before * page_size,
+            size_after: pages_after * page_size_after,
+            reclaimed_pages: pages_before.saturating_sub(pages_after),
+            free_pages_before,
+        })
+    }
+}
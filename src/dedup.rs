@@ -0,0 +1,110 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::cache::ChangeStamp;
+use crate::{Error, Result, TemplateDatabase, UpdatedValues};
+
+#[derive(Debug, Clone, Default)]
+struct KnownSubs {
+    locked: bool,
+    values: HashSet<String>,
+}
+
+// Remembers the substitutes of the templates it has inserted into, so submitting a value
+// that is already stored returns without touching SQLite beyond a cheap staleness check.
+// Templates are loaded on first use and dropped whenever the database changes by any other
+// means than this filter, so a skipped insert is always a real duplicate.
+#[derive(Debug, Clone, Default)]
+pub struct InsertFilter {
+    templates: HashMap<String, KnownSubs>,
+    stamp: Option<ChangeStamp>,
+}
+
+fn key(value: &str) -> String {
+    // Matches the NOCASE collation of template and substitute names.
+    value.to_ascii_lowercase()
+}
+
+impl InsertFilter {
+    pub fn new() -> InsertFilter {
+        InsertFilter::default()
+    }
+
+    fn known(&mut self, db: &TemplateDatabase, template: &str) -> Result<&mut KnownSubs> {
+        let stamp = db.change_stamp()?;
+        if self.stamp != Some(stamp) {
+            self.templates.clear();
+            self.stamp = Some(stamp);
+        }
+
+        let key = key(template);
+        if !self.templates.contains_key(&key) {
+            let mut known = KnownSubs::default();
+            if db.find_template(template)?.is_some() {
+                known.locked = db.is_template_locked(template)?;
+                known.values = db
+                    .get_subs(template)?
+                    .iter()
+                    .map(|x| self::key(x))
+                    .collect();
+            }
+            self.templates.insert(key.clone(), known);
+        }
+
+        Ok(self
+            .templates
+            .get_mut(&key)
+            .expect("template was just loaded"))
+    }
+
+    fn record_own_changes(&mut self, db: &TemplateDatabase) {
+        self.stamp = self.stamp.map(|x| x.with_own_changes(db));
+    }
+
+    pub fn insert_sub(
+        &mut self,
+        db: &mut TemplateDatabase,
+        template: &str,
+        substitute: &str,
+    ) -> Result<bool> {
+        let known = self.known(db, template)?;
+        if known.locked {
+            return Err(Error::TemplateLocked(template.to_string()));
+        }
+        if known.values.contains(&key(substitute)) {
+            return Ok(false);
+        }
+
+        let inserted = db.insert_sub(template, substitute)?;
+        known.values.insert(key(substitute));
+        self.record_own_changes(db);
+
+        Ok(inserted)
+    }
+
+    pub fn insert_subs<'a>(
+        &mut self,
+        db: &mut TemplateDatabase,
+        template: &'a str,
+        substitutes: &[&'a str],
+    ) -> Result<UpdatedValues<'a>> {
+        let known = self.known(db, template)?;
+        if known.locked {
+            return Err(Error::TemplateLocked(template.to_string()));
+        }
+
+        let unknown: Vec<&'a str> = substitutes
+            .iter()
+            .copied()
+            .filter(|x| !known.values.contains(&key(x)))
+            .collect();
+        if unknown.is_empty() {
+            return Ok(UpdatedValues::new());
+        }
+
+        let inserted = db.insert_subs(template, Some(&unknown))?;
+        known.values.extend(unknown.iter().map(|x| key(x)));
+        self.record_own_changes(db);
+
+        Ok(inserted)
+    }
+}
@@ -0,0 +1,93 @@
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+#[cfg(feature = "sqlite")]
+use std::io::{BufWriter, Write};
+
+use crate::Rng;
+#[cfg(feature = "sqlite")]
+use crate::{Result, TemplateDatabase};
+
+// Read-only templates compiled into the binary, usually produced by
+// `TemplateDatabase::export_rust_source`. Templates must be sorted by their ASCII lowercase
+// name, which the generated source guarantees.
+#[derive(Debug, Clone, Copy)]
+pub struct StaticTemplates {
+    templates: &'static [(&'static str, &'static [&'static str])],
+}
+
+fn cmp_ignore_ascii_case(a: &str, b: &str) -> Ordering {
+    a.bytes()
+        .map(|x| x.to_ascii_lowercase())
+        .cmp(b.bytes().map(|x| x.to_ascii_lowercase()))
+}
+
+impl StaticTemplates {
+    pub const fn new(templates: &'static [(&'static str, &'static [&'static str])]) -> Self {
+        StaticTemplates { templates }
+    }
+
+    pub fn get_templates(&self) -> Vec<&'static str> {
+        self.templates.iter().map(|(name, _)| *name).collect()
+    }
+
+    pub fn get_subs(&self, template: &str) -> Option<&'static [&'static str]> {
+        self.templates
+            .binary_search_by(|(name, _)| cmp_ignore_ascii_case(name, template))
+            .ok()
+            .map(|index| self.templates[index].1)
+    }
+
+    pub fn get_random_sub(&self, template: &str, rng: &mut Rng) -> Option<&'static str> {
+        let subs = self.get_subs(template)?;
+        if subs.is_empty() {
+            return Some("");
+        }
+        Some(subs[rng.below(subs.len())])
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl TemplateDatabase {
+    // Writes the whole database as a Rust source file defining
+    // `pub static <name>: StaticTemplates`, for builds that ship without SQLite. Static
+    // templates have no weights, so disabled substitutes are left out.
+    pub fn export_rust_source<W: Write>(&self, name: &str, writer: W) -> Result<()> {
+        let mut templates = Vec::new();
+        for template in self.get_templates()? {
+            let subs = self.query().template(&template).enabled(true).fetch()?;
+            templates.push((template, subs));
+        }
+        templates.sort_by_key(|(template, _)| template.to_ascii_lowercase());
+
+        let mut writer = BufWriter::new(writer);
+
+        writeln!(
+            writer,
+            "// Generated by template_substitution_database, do not edit."
+        )?;
+        writeln!(
+            writer,
+            "pub static {}: template_substitution_database::StaticTemplates =",
+            name
+        )?;
+        writeln!(
+            writer,
+            "    template_substitution_database::StaticTemplates::new(&["
+        )?;
+        for (template, subs) in &templates {
+            write!(writer, "        ({:?}, &[", template)?;
+            for (i, sub) in subs.iter().enumerate() {
+                if i > 0 {
+                    write!(writer, ", ")?;
+                }
+                write!(writer, "{:?}", sub)?;
+            }
+            writeln!(writer, "]),")?;
+        }
+        writeln!(writer, "    ]);")?;
+
+        writer.flush()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum Error {
+    Sqlite(rusqlite::Error),
+    Io(std::io::Error),
+    NotEnoughUniqueOutputs {
+        requested: usize,
+        found: usize,
+    },
+    Conflict {
+        template: String,
+        value: String,
+    },
+    NotReadOnly(String),
+    TemplateLocked(String),
+    PackInstalled(String),
+    PatternExists(String),
+    InvalidVersion(String),
+    MissingDependency {
+        pack: String,
+        dependency: String,
+    },
+    IncompatibleDependency {
+        pack: String,
+        dependency: String,
+        required: String,
+        installed: String,
+    },
+    PackRequired {
+        pack: String,
+        dependent: String,
+    },
+    Timeout(Duration),
+    TemplateReferenced {
+        template: String,
+        patterns: Vec<String>,
+    },
+    InvalidFormat {
+        line: usize,
+        message: String,
+    },
+    // Usage tracking is off on this database, so unused substitutes can't be told apart.
+    UsageNotTracked,
+    // A chunked read was asked for zero items, which would never make progress.
+    ZeroLimit,
+    // A database error raised by a public operation, along with what it was working on.
+    Context {
+        operation: &'static str,
+        template: Option<String>,
+        value: Option<String>,
+        source: Box<rusqlite::Error>,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Sqlite(err) => write!(f, "database error: {}", err),
+            Error::Io(err) => write!(f, "io error: {}", err),
+            Error::NotEnoughUniqueOutputs { requested, found } => write!(
+                f,
+                "requested {} unique outputs but only {} could be generated",
+                requested, found
+            ),
+            Error::Conflict { template, value } => {
+                write!(f, "'{}' already exists in template '{}'", value, template)
+            }
+            Error::NotReadOnly(sql) => write!(f, "statement is not a read-only query: {}", sql),
+            Error::TemplateLocked(template) => write!(f, "template '{}' is locked", template),
+            Error::PackInstalled(pack) => write!(f, "pack '{}' is already installed", pack),
+            Error::PatternExists(pattern) => write!(f, "pattern '{}' already exists", pattern),
+            Error::InvalidVersion(version) => write!(f, "invalid version: {}", version),
+            Error::MissingDependency { pack, dependency } => write!(
+                f,
+                "pack '{}' depends on '{}', which is not installed",
+                pack, dependency
+            ),
+            Error::IncompatibleDependency {
+                pack,
+                dependency,
+                required,
+                installed,
+            } => write!(
+                f,
+                "pack '{}' requires '{}' {} but {} is installed",
+                pack, dependency, required, installed
+            ),
+            Error::PackRequired { pack, dependent } => {
+                write!(f, "pack '{}' is required by '{}'", pack, dependent)
+            }
+            Error::Timeout(timeout) => write!(f, "operation timed out after {:?}", timeout),
+            Error::TemplateReferenced { template, patterns } => write!(
+                f,
+                "template '{}' is used by patterns: {}",
+                template,
+                patterns.join(", ")
+            ),
+            Error::InvalidFormat { line, message } => {
+                write!(f, "invalid input on line {}: {}", line, message)
+            }
+            Error::UsageNotTracked => write!(f, "usage tracking is not enabled"),
+            Error::ZeroLimit => write!(f, "limit must be at least 1"),
+            Error::Context {
+                operation,
+                template,
+                value,
+                source,
+            } => {
+                write!(f, "{} failed", operation)?;
+                if let Some(template) = template {
+                    write!(f, " for template '{}'", template)?;
+                }
+                if let Some(value) = value {
+                    write!(f, " value '{}'", value)?;
+                }
+                write!(f, ": {}", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Sqlite(err) => Some(err),
+            Error::Io(err) => Some(err),
+            Error::Context { source, .. } => Some(source.as_ref()),
+            Error::NotEnoughUniqueOutputs { .. }
+            | Error::Conflict { .. }
+            | Error::NotReadOnly(_)
+            | Error::TemplateLocked(_)
+            | Error::PackInstalled(_)
+            | Error::PatternExists(_)
+            | Error::InvalidVersion(_)
+            | Error::MissingDependency { .. }
+            | Error::IncompatibleDependency { .. }
+            | Error::PackRequired { .. }
+            | Error::Timeout(_)
+            | Error::TemplateReferenced { .. }
+            | Error::InvalidFormat { .. }
+            | Error::UsageNotTracked
+            | Error::ZeroLimit => None,
+        }
+    }
+}
+
+impl Error {
+    // The underlying SQLite error, with or without operation context.
+    pub fn sqlite(&self) -> Option<&rusqlite::Error> {
+        match self {
+            Error::Sqlite(err) => Some(err),
+            Error::Context { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// Runs `f` on behalf of `operation`, attaching the operation and its arguments to a SQLite
+// error it fails with. Errors of this crate's own making already say what went wrong.
+pub(crate) fn with_context<T>(
+    operation: &'static str,
+    template: Option<&str>,
+    value: Option<&str>,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    f().map_err(|err| match err {
+        Error::Sqlite(source) => Error::Context {
+            operation,
+            template: template.map(|x| x.to_string()),
+            value: value.map(|x| x.to_string()),
+            source: Box::new(source),
+        },
+        err => err,
+    })
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Sqlite(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
@@ -0,0 +1,119 @@
+use crate::{Error, Result, TemplateDatabase};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExportPhase {
+    Templates,
+    Substitutes,
+}
+
+// Position in an export. Templates are exported first, then substitutes, each in id order,
+// so a cursor stays valid across restarts and concurrent inserts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExportCursor {
+    phase: ExportPhase,
+    after_id: i64,
+}
+
+impl ExportCursor {
+    pub fn to_token(&self) -> String {
+        match self.phase {
+            ExportPhase::Templates => format!("t:{}", self.after_id),
+            ExportPhase::Substitutes => format!("s:{}", self.after_id),
+        }
+    }
+
+    pub fn from_token(token: &str) -> Option<ExportCursor> {
+        let (phase, after_id) = token.split_once(':')?;
+        let phase = match phase {
+            "t" => ExportPhase::Templates,
+            "s" => ExportPhase::Substitutes,
+            _ => return None,
+        };
+        Some(ExportCursor {
+            phase,
+            after_id: after_id.parse().ok()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExportItem {
+    Template(String),
+    Substitute { template: String, name: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportChunk {
+    pub items: Vec<ExportItem>,
+    pub next: Option<ExportCursor>,
+}
+
+impl TemplateDatabase {
+    // Returns up to `limit` items following `cursor`, or from the start when `cursor` is
+    // `None`. `next` is `None` once everything has been exported. A `limit` of 0 fails with
+    // `Error::ZeroLimit`.
+    pub fn export_chunk(&self, cursor: Option<&ExportCursor>, limit: usize) -> Result<ExportChunk> {
+        if limit == 0 {
+            return Err(Error::ZeroLimit);
+        }
+        let mut cursor = cursor.copied().unwrap_or(ExportCursor {
+            phase: ExportPhase::Templates,
+            after_id: 0,
+        });
+        let mut items = Vec::new();
+        let mut finished = false;
+
+        let tx = self.read_transaction()?;
+
+        if cursor.phase == ExportPhase::Templates {
+            let mut stmt = tx.prepare_cached(
+                "SELECT id, name FROM templates WHERE id > ?1 ORDER BY id LIMIT ?2",
+            )?;
+            let mut rows = stmt.query((cursor.after_id, limit as i64))?;
+
+            while let Some(row) = rows.next()? {
+                cursor.after_id = row.get(0)?;
+                items.push(ExportItem::Template(row.get(1)?));
+            }
+
+            if items.len() < limit {
+                cursor = ExportCursor {
+                    phase: ExportPhase::Substitutes,
+                    after_id: 0,
+                };
+            }
+        }
+
+        if cursor.phase == ExportPhase::Substitutes && items.len() < limit {
+            let mut stmt = tx.prepare_cached(
+                "SELECT substitutes.id, templates.name, substitutes.name
+                 FROM substitutes
+                 JOIN templates ON templates.id = substitutes.template_id
+                 WHERE substitutes.id > ?1
+                 ORDER BY substitutes.id
+                 LIMIT ?2",
+            )?;
+            let remaining = limit - items.len();
+            let mut rows = stmt.query((cursor.after_id, remaining as i64))?;
+            let mut fetched = 0;
+
+            while let Some(row) = rows.next()? {
+                cursor.after_id = row.get(0)?;
+                items.push(ExportItem::Substitute {
+                    template: row.get(1)?,
+                    name: row.get(2)?,
+                });
+                fetched += 1;
+            }
+
+            finished = fetched < remaining;
+        }
+
+        tx.commit()?;
+
+        Ok(ExportChunk {
+            items,
+            next: (!finished).then_some(cursor),
+        })
+    }
+}
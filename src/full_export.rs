@@ -0,0 +1,403 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufWriter, Write};
+
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::error::with_context;
+use crate::{ConflictPolicy, Error, Result, TemplateDatabase};
+
+const HEADER: &str = "template-substitution-database full 1";
+
+// Record kind, the query that exports it and the statement that restores it. Rows are
+// written in this order, so everything a row references is restored before it.
+const RECORDS: &[(&str, &str, &str)] = &[
+    (
+        "template",
+        "SELECT id, name, locked FROM templates ORDER BY id",
+        "INSERT INTO templates (id, name, locked) VALUES (?1, ?2, ?3)",
+    ),
+    (
+        "substitute",
+        "SELECT id, template_id, name, weight, metadata, use_count, last_used, created_at
+         FROM substitutes ORDER BY id",
+        "INSERT INTO substitutes
+         (id, template_id, name, weight, metadata, use_count, last_used, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    ),
+    (
+        "tag",
+        "SELECT substitute_id, tag FROM substitute_tags ORDER BY substitute_id, rowid",
+        "INSERT OR IGNORE INTO substitute_tags (substitute_id, tag) VALUES (?1, ?2)",
+    ),
+    (
+        "pattern",
+        "SELECT id, name, pattern FROM patterns ORDER BY id",
+        "INSERT INTO patterns (id, name, pattern) VALUES (?1, ?2, ?3)",
+    ),
+    (
+        "pack",
+        "SELECT id, name, version FROM packs ORDER BY id",
+        "INSERT INTO packs (id, name, version) VALUES (?1, ?2, ?3)",
+    ),
+    (
+        "pack_item",
+        "SELECT pack_id, kind, item_id FROM pack_contents ORDER BY rowid",
+        "INSERT INTO pack_contents (pack_id, kind, item_id) VALUES (?1, ?2, ?3)",
+    ),
+    (
+        "pack_dependency",
+        "SELECT pack_id, dependency, requirement FROM pack_dependencies ORDER BY rowid",
+        "INSERT INTO pack_dependencies (pack_id, dependency, requirement) VALUES (?1, ?2, ?3)",
+    ),
+    (
+        "usage_tracking",
+        "SELECT started_at FROM usage_tracking",
+        "INSERT OR REPLACE INTO usage_tracking (id, started_at) VALUES (1, ?1)",
+    ),
+];
+
+fn escape_field(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn unescape_field(field: &str) -> Option<Value> {
+    if field == "\\N" {
+        return Some(Value::Null);
+    }
+
+    let mut text = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => text.push('\\'),
+            't' => text.push('\t'),
+            'n' => text.push('\n'),
+            'r' => text.push('\r'),
+            _ => return None,
+        }
+    }
+    // Column affinity turns numeric text back into integers on insert.
+    Some(Value::Text(text))
+}
+
+// Integer fields come back as text, see `unescape_field`.
+fn integer_field(value: &Value) -> Option<i64> {
+    match value {
+        Value::Integer(x) => Some(*x),
+        Value::Text(x) => x.parse().ok(),
+        _ => None,
+    }
+}
+
+fn invalid(line: usize, message: &str) -> Error {
+    Error::InvalidFormat {
+        line,
+        message: message.to_string(),
+    }
+}
+
+impl TemplateDatabase {
+    // Writes every row with all its attributes (ids, weights, tags, metadata, usage
+    // counters, timestamps, locks, patterns, packs and when usage tracking started) as tab
+    // separated text, one row per line. `import_full` restores it exactly.
+    pub fn export_full<W: Write>(&self, writer: W) -> Result<()> {
+        let tx = self.read_transaction()?;
+        let mut writer = BufWriter::new(writer);
+        let mut line = String::new();
+
+        writeln!(writer, "{}", HEADER)?;
+
+        for (kind, select, _) in RECORDS {
+            let mut stmt = tx.prepare(select)?;
+            let columns = stmt.column_count();
+            let mut rows = stmt.query([])?;
+
+            while let Some(row) = rows.next()? {
+                line.clear();
+                line.push_str(kind);
+                for index in 0..columns {
+                    line.push('\t');
+                    match row.get_ref(index)? {
+                        ValueRef::Null => line.push_str("\\N"),
+                        ValueRef::Integer(x) => line.push_str(&x.to_string()),
+                        ValueRef::Real(x) => line.push_str(&x.to_string()),
+                        ValueRef::Text(x) => escape_field(&String::from_utf8_lossy(x), &mut line),
+                        ValueRef::Blob(_) => {
+                            return Err(rusqlite::Error::InvalidColumnType(
+                                index,
+                                kind.to_string(),
+                                rusqlite::types::Type::Blob,
+                            )
+                            .into())
+                        }
+                    }
+                }
+                line.push('\n');
+                writer.write_all(line.as_bytes())?;
+            }
+        }
+
+        tx.commit()?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    // Applies `policy` to a substitute record whose value is already stored in its template,
+    // either earlier in the dump or in the database being merged into. Returns the row its
+    // tags now belong to, `None` when they are dropped, or no answer when there is no
+    // conflict.
+    fn execute_fold_substitute(
+        tx: &Connection,
+        values: &[Value],
+        policy: ConflictPolicy,
+    ) -> Result<Option<Option<i64>>> {
+        let existing: Option<(i64, String)> = tx
+            .query_row(
+                "SELECT substitutes.id, templates.name
+                 FROM substitutes
+                 JOIN templates ON templates.id = substitutes.template_id
+                 WHERE substitutes.template_id = ?1 AND substitutes.name = ?2",
+                [&values[1], &values[2]],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let Some((id, template)) = existing else {
+            return Ok(None);
+        };
+
+        match policy {
+            ConflictPolicy::Skip => Ok(Some(None)),
+            ConflictPolicy::Error => {
+                let value = match &values[2] {
+                    Value::Text(x) => x.clone(),
+                    _ => String::new(),
+                };
+                Err(Error::Conflict { template, value })
+            }
+            ConflictPolicy::Overwrite => {
+                tx.execute(
+                    "UPDATE substitutes
+                     SET name = ?2, weight = ?3, metadata = ?4, use_count = ?5, last_used = ?6,
+                         created_at = ?7
+                     WHERE id = ?1",
+                    rusqlite::params_from_iter(
+                        std::iter::once(&Value::Integer(id)).chain(&values[2..]),
+                    ),
+                )?;
+                tx.execute("DELETE FROM substitute_tags WHERE substitute_id = ?1", [id])?;
+                Ok(Some(Some(id)))
+            }
+            ConflictPolicy::MergeMetadata => {
+                tx.execute(
+                    "UPDATE substitutes SET weight = ?2, metadata = COALESCE(?3, metadata)
+                     WHERE id = ?1",
+                    (id, &values[3], &values[4]),
+                )?;
+                Ok(Some(Some(id)))
+            }
+        }
+    }
+
+    // Matches a template, pattern or pack record against what is already stored when merging
+    // into a non-empty database. Returns the stored row the record stands for, or no answer
+    // when it is new.
+    fn execute_match_record(
+        tx: &Connection,
+        kind: &str,
+        values: &[Value],
+        policy: ConflictPolicy,
+    ) -> Result<Option<i64>> {
+        let query = match kind {
+            "template" => "SELECT id FROM templates WHERE name = ?1",
+            "pattern" => "SELECT id FROM patterns WHERE name = ?1",
+            "pack" => "SELECT id FROM packs WHERE name = ?1",
+            _ => return Ok(None),
+        };
+        let Some(id) = tx
+            .query_row(query, [&values[1]], |row| row.get::<_, i64>(0))
+            .optional()?
+        else {
+            return Ok(None);
+        };
+
+        let name = match &values[1] {
+            Value::Text(x) => x.clone(),
+            _ => String::new(),
+        };
+        match (kind, policy) {
+            ("pack", _) => return Err(Error::PackInstalled(name)),
+            ("pattern", ConflictPolicy::Error) => return Err(Error::PatternExists(name)),
+            ("pattern", ConflictPolicy::Overwrite) => {
+                tx.execute(
+                    "UPDATE patterns SET name = ?2, pattern = ?3 WHERE id = ?1",
+                    (id, &values[1], &values[2]),
+                )?;
+            }
+            _ => {}
+        }
+        Ok(Some(id))
+    }
+
+    // Restores an `export_full` dump in one transaction. Into an empty database everything
+    // comes back exactly, ids included, and `policy` only handles substitutes repeated within
+    // the dump. Into a non-empty database the dump is merged: rows get new ids, templates and
+    // patterns are matched by name, substitutes and patterns that already exist are handled
+    // by `policy`, and a pack that is already installed fails with `Error::PackInstalled`.
+    // As with `install_pack`, a restored pack only owns the rows it added. Usage tracking
+    // stays as it was in the database, counted from the later of the two starts. Anything
+    // malformed fails with `Error::InvalidFormat` and leaves the database untouched.
+    pub fn import_full<R: BufRead>(&mut self, reader: R, policy: ConflictPolicy) -> Result<()> {
+        with_context("import_full", None, None, || {
+            let tx = self.db.savepoint()?;
+
+            let rows: i64 = tx.query_row(
+                "SELECT (SELECT COUNT(*) FROM templates) + (SELECT COUNT(*) FROM patterns)
+                      + (SELECT COUNT(*) FROM packs)",
+                [],
+                |row| row.get(0),
+            )?;
+            let merging = rows > 0;
+
+            // Rows without a creation time must stay that way.
+            tx.execute("DROP TRIGGER IF EXISTS set_substitute_created_at", [])?;
+
+            // Records stored under another id, by kind and id in the dump, `None` when they
+            // were dropped. Those folded into an existing row are in `matched` as well.
+            let mut ids: HashMap<(&str, i64), Option<i64>> = HashMap::new();
+            let mut matched = HashSet::new();
+            let mut tracking_started: Option<Value> = None;
+
+            let mut lines = reader.lines();
+            if lines.next().transpose()?.as_deref() != Some(HEADER) {
+                return Err(invalid(1, "missing header"));
+            }
+
+            for (index, line) in lines.enumerate() {
+                let line_number = index + 2;
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+
+                let mut fields = line.split('\t');
+                let kind = fields.next().unwrap_or_default();
+                let Some((kind, select, insert)) = RECORDS.iter().find(|(x, _, _)| *x == kind)
+                else {
+                    return Err(invalid(line_number, "unknown record kind"));
+                };
+
+                let mut values = fields
+                    .map(unescape_field)
+                    .collect::<Option<Vec<Value>>>()
+                    .ok_or_else(|| invalid(line_number, "bad escape sequence"))?;
+                if values.len() != tx.prepare_cached(select)?.column_count() {
+                    return Err(invalid(line_number, "wrong number of fields"));
+                }
+
+                // Fields holding the id of another record, with that record's kind.
+                let references: &[(usize, &str)] = match *kind {
+                    "substitute" => &[(1, "template")],
+                    "tag" => &[(0, "substitute")],
+                    "pack_item" => match &values[1] {
+                        Value::Text(x) if x == "template" => &[(0, "pack"), (2, "template")],
+                        Value::Text(x) if x == "substitute" => &[(0, "pack"), (2, "substitute")],
+                        Value::Text(x) if x == "pattern" => &[(0, "pack"), (2, "pattern")],
+                        _ => &[(0, "pack")],
+                    },
+                    "pack_dependency" => &[(0, "pack")],
+                    _ => &[],
+                };
+                // Templates that came with the dump may be locked, existing ones must not be.
+                let into_existing = *kind == "substitute"
+                    && integer_field(&values[1])
+                        .is_some_and(|x| matched.contains(&("template", x)));
+                let mut dropped = false;
+                for (field, target) in references {
+                    let Some(old_id) = integer_field(&values[*field]) else {
+                        continue;
+                    };
+                    // Packs never own rows they did not add.
+                    if *kind == "pack_item" && *field == 2 && matched.contains(&(*target, old_id)) {
+                        dropped = true;
+                    }
+                    match ids.get(&(*target, old_id)) {
+                        Some(None) => dropped = true,
+                        Some(Some(id)) => values[*field] = Value::Integer(*id),
+                        None => {}
+                    }
+                }
+                if dropped {
+                    continue;
+                }
+
+                let record_id = integer_field(&values[0]);
+                let existing = match *kind {
+                    "usage_tracking" if merging => {
+                        tracking_started = Some(values[0].clone());
+                        continue;
+                    }
+                    "substitute" => {
+                        if let (true, Some(template_id)) =
+                            (into_existing, integer_field(&values[1]))
+                        {
+                            Self::execute_check_unlocked_id(&tx, template_id)?;
+                        }
+                        Self::execute_fold_substitute(&tx, &values, policy)?
+                    }
+                    _ if merging => {
+                        Self::execute_match_record(&tx, kind, &values, policy)?.map(Some)
+                    }
+                    _ => None,
+                };
+                if let Some(id) = existing {
+                    if let Some(record_id) = record_id {
+                        ids.insert((*kind, record_id), id);
+                        matched.insert((*kind, record_id));
+                    }
+                    continue;
+                }
+
+                let keyed = matches!(*kind, "template" | "substitute" | "pattern" | "pack");
+                if merging && keyed {
+                    values[0] = Value::Null;
+                }
+                tx.prepare_cached(insert)?
+                    .execute(rusqlite::params_from_iter(values))?;
+                if let (true, true, Some(record_id)) = (merging, keyed, record_id) {
+                    ids.insert((*kind, record_id), Some(tx.last_insert_rowid()));
+                }
+            }
+
+            // Merged rows were only counted since the later of the two starts, and not at all
+            // when the dump was not tracked, in which case counting starts over now.
+            if merging {
+                tx.execute(
+                    "UPDATE usage_tracking
+                     SET started_at = MAX(started_at,
+                         COALESCE(?1, CAST(strftime('%s', 'now') AS INTEGER)))",
+                    [tracking_started],
+                )?;
+            }
+
+            Self::create_created_at_trigger(&tx)?;
+            tx.commit()?;
+            self.track_usage = Self::execute_usage_tracked(&self.db)?;
+
+            Ok(())
+        })
+    }
+}
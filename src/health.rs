@@ -0,0 +1,49 @@
+use std::time::{Duration, Instant};
+
+use crate::{TemplateDatabase, DATABASE_VERSION};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthStatus {
+    // Reachable, readable and on the schema version this build expects.
+    pub healthy: bool,
+    // Time taken by the checks themselves.
+    pub latency: Duration,
+    // `None` when the version could not be read.
+    pub schema_version: Option<i32>,
+    pub expected_schema_version: i32,
+    // What went wrong, when a check failed outright.
+    pub error: Option<String>,
+}
+
+impl TemplateDatabase {
+    // Cheap readiness probe: reads the schema version and touches the templates table in
+    // one read transaction. Failures are reported in the status rather than returned, so a
+    // probe can always tell an unreachable database from a mismatched schema.
+    pub fn health_check(&self) -> HealthStatus {
+        let start = Instant::now();
+        let checked = self.read_transaction().and_then(|tx| {
+            let version = Self::get_schema_version(&tx)?;
+            tx.query_row("SELECT EXISTS (SELECT 1 FROM templates)", [], |_| Ok(()))?;
+            tx.commit()?;
+            Ok(version)
+        });
+        let latency = start.elapsed();
+
+        match checked {
+            Ok(version) => HealthStatus {
+                healthy: version == DATABASE_VERSION,
+                latency,
+                schema_version: Some(version),
+                expected_schema_version: DATABASE_VERSION,
+                error: None,
+            },
+            Err(err) => HealthStatus {
+                healthy: false,
+                latency,
+                schema_version: None,
+                expected_schema_version: DATABASE_VERSION,
+                error: Some(err.to_string()),
+            },
+        }
+    }
+}
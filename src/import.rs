@@ -0,0 +1,430 @@
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::error::with_context;
+use crate::{Error, ExportItem, Result, TemplateDatabase, UpdatedValues};
+
+// What to do when an imported substitute already exists in its template. Substitute names
+// compare case-insensitively, so an existing value may differ from the incoming one in case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    // Keeps the stored substitute as it is.
+    #[default]
+    Skip,
+    // Takes the incoming spelling along with any weight, tags and metadata it carries.
+    Overwrite,
+    // Fails with `Error::Conflict`.
+    Error,
+    // Keeps the stored spelling, takes any incoming weight and metadata and adds incoming
+    // tags to the stored ones.
+    MergeMetadata,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InsertOutcome {
+    Inserted,
+    Overwritten,
+    Skipped,
+}
+
+// A substitute with optional attributes for `upsert_subs`. Attributes left as `None` keep
+// their stored value, or the default for newly inserted substitutes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubEntry<'a> {
+    pub value: &'a str,
+    pub weight: Option<i64>,
+    pub tags: Option<&'a [&'a str]>,
+    pub metadata: Option<&'a str>,
+}
+
+impl<'a> SubEntry<'a> {
+    pub fn new(value: &'a str) -> SubEntry<'a> {
+        SubEntry {
+            value,
+            weight: None,
+            tags: None,
+            metadata: None,
+        }
+    }
+
+    pub fn weight(mut self, weight: i64) -> Self {
+        self.weight = Some(weight);
+        self
+    }
+
+    pub fn tags(mut self, tags: &'a [&'a str]) -> Self {
+        self.tags = Some(tags);
+        self
+    }
+
+    pub fn metadata(mut self, metadata: &'a str) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ImportReport {
+    pub templates_created: usize,
+    pub inserted: usize,
+    pub overwritten: usize,
+    pub skipped: usize,
+}
+
+impl ImportReport {
+    pub(crate) fn record(&mut self, outcome: InsertOutcome) {
+        match outcome {
+            InsertOutcome::Inserted => self.inserted += 1,
+            InsertOutcome::Overwritten => self.overwritten += 1,
+            InsertOutcome::Skipped => self.skipped += 1,
+        }
+    }
+}
+
+// A substitute already stored under the value of an entry being written.
+struct StoredEntry {
+    id: i64,
+    name: String,
+    weight: i64,
+    metadata: Option<String>,
+}
+
+impl TemplateDatabase {
+    fn execute_find_entry(
+        tx: &Connection,
+        template_id: &str,
+        value: &str,
+    ) -> rusqlite::Result<Option<StoredEntry>> {
+        tx.query_row(
+            "SELECT id, name, weight, metadata FROM substitutes
+             WHERE template_id = ?1 AND name = ?2",
+            [template_id, value],
+            |row| {
+                Ok(StoredEntry {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    weight: row.get(2)?,
+                    metadata: row.get(3)?,
+                })
+            },
+        )
+        .optional()
+    }
+
+    fn execute_insert_entry(
+        tx: &Connection,
+        template_id: &str,
+        entry: &SubEntry,
+    ) -> rusqlite::Result<i64> {
+        tx.execute(
+            "INSERT INTO substitutes (name, template_id, weight, metadata)
+             VALUES (?1, ?2, COALESCE(?3, 1), ?4)",
+            (entry.value, template_id, entry.weight, entry.metadata),
+        )?;
+        let id = tx.last_insert_rowid();
+        if let Some(tags) = entry.tags {
+            Self::execute_set_tags(tx, id, tags)?;
+        }
+        Ok(id)
+    }
+
+    // Applies the weight, metadata and tags `entry` sets to a stored substitute. Tags compare
+    // ignoring case and order, and are added to the stored ones when `merge_tags` is set
+    // instead of replacing them. Returns whether anything changed.
+    fn execute_update_entry(
+        tx: &Connection,
+        stored: &StoredEntry,
+        entry: &SubEntry,
+        merge_tags: bool,
+    ) -> rusqlite::Result<bool> {
+        let mut changed = false;
+
+        if let Some(new_weight) = entry.weight.filter(|x| *x != stored.weight) {
+            tx.execute(
+                "UPDATE substitutes SET weight = ?1 WHERE id = ?2",
+                (new_weight, stored.id),
+            )?;
+            changed = true;
+        }
+
+        if let Some(new_metadata) = entry
+            .metadata
+            .filter(|x| stored.metadata.as_deref() != Some(*x))
+        {
+            tx.execute(
+                "UPDATE substitutes SET metadata = ?1 WHERE id = ?2",
+                (new_metadata, stored.id),
+            )?;
+            changed = true;
+        }
+
+        if let Some(tags) = entry.tags {
+            let stored_tags = Self::execute_get_tags(tx, stored.id)?;
+            let mut new_tags: Vec<&str> = if merge_tags {
+                stored_tags.iter().map(|x| x.as_str()).collect()
+            } else {
+                Vec::new()
+            };
+            for tag in tags {
+                if !new_tags.iter().any(|x| x.eq_ignore_ascii_case(tag)) {
+                    new_tags.push(tag);
+                }
+            }
+            let same = new_tags.len() == stored_tags.len()
+                && new_tags
+                    .iter()
+                    .all(|x| stored_tags.iter().any(|y| y.eq_ignore_ascii_case(x)));
+            if !same {
+                Self::execute_set_tags(tx, stored.id, &new_tags)?;
+                changed = true;
+            }
+        }
+
+        Ok(changed)
+    }
+
+    // Inserts `entry`, or applies `policy` when its value is already in the template.
+    // Returns the outcome and the id of the stored substitute. A conflict that changes
+    // nothing counts as skipped.
+    pub(crate) fn execute_insert_entry_with_policy(
+        tx: &Connection,
+        template: &str,
+        template_id: &str,
+        entry: &SubEntry,
+        policy: ConflictPolicy,
+    ) -> Result<(InsertOutcome, i64)> {
+        let Some(stored) = Self::execute_find_entry(tx, template_id, entry.value)? else {
+            let id = Self::execute_insert_entry(tx, template_id, entry)?;
+            return Ok((InsertOutcome::Inserted, id));
+        };
+
+        let mut changed = false;
+
+        match policy {
+            ConflictPolicy::Skip => return Ok((InsertOutcome::Skipped, stored.id)),
+            ConflictPolicy::Error => {
+                return Err(Error::Conflict {
+                    template: template.to_string(),
+                    value: entry.value.to_string(),
+                })
+            }
+            ConflictPolicy::Overwrite if stored.name != entry.value => {
+                tx.execute(
+                    "UPDATE substitutes SET name = ?1 WHERE id = ?2",
+                    (entry.value, stored.id),
+                )?;
+                changed = true;
+            }
+            ConflictPolicy::Overwrite | ConflictPolicy::MergeMetadata => {}
+        }
+
+        let merge_tags = policy == ConflictPolicy::MergeMetadata;
+        if Self::execute_update_entry(tx, &stored, entry, merge_tags)? {
+            changed = true;
+        }
+
+        let outcome = if changed {
+            InsertOutcome::Overwritten
+        } else {
+            InsertOutcome::Skipped
+        };
+        Ok((outcome, stored.id))
+    }
+
+    // Inserts `entries` into `template`, creating it when needed, and counts the outcomes.
+    pub(crate) fn execute_import_entries(
+        tx: &Connection,
+        template: &str,
+        entries: &[SubEntry],
+        policy: ConflictPolicy,
+        report: &mut ImportReport,
+    ) -> Result<()> {
+        Self::execute_check_unlocked(tx, template)?;
+        if Self::execute_insert_template(tx, template)? {
+            report.templates_created += 1;
+        }
+        let template_id = Self::find_template_id_with_transaction(tx, template)?;
+
+        for entry in entries {
+            let (outcome, _) =
+                Self::execute_insert_entry_with_policy(tx, template, &template_id, entry, policy)?;
+            report.record(outcome);
+        }
+        Ok(())
+    }
+
+    pub fn insert_subs_with_policy<'a>(
+        &mut self,
+        template: &'a str,
+        substitutes: &[&'a str],
+        policy: ConflictPolicy,
+    ) -> Result<UpdatedValues<'a>> {
+        with_context("insert_subs_with_policy", Some(template), None, || {
+            let tx = self.db.savepoint()?;
+            Self::execute_check_unlocked(&tx, template)?;
+
+            Self::execute_insert_template(&tx, template)?;
+            let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+            let mut change_log = UpdatedValues::new();
+
+            for sub in substitutes {
+                let (outcome, _) = Self::execute_insert_entry_with_policy(
+                    &tx,
+                    template,
+                    &template_id,
+                    &SubEntry::new(sub),
+                    policy,
+                )?;
+                if outcome != InsertOutcome::Skipped {
+                    change_log.push(*sub);
+                }
+            }
+
+            tx.commit()?;
+
+            Ok(change_log)
+        })
+    }
+
+    pub(crate) fn execute_get_tags(tx: &Connection, id: i64) -> rusqlite::Result<Vec<String>> {
+        let mut stmt = tx.prepare_cached(
+            "SELECT tag FROM substitute_tags WHERE substitute_id = ?1 ORDER BY LOWER(tag)",
+        )?;
+        let tags = stmt.query_map([id], |row| row.get(0))?;
+        tags.collect()
+    }
+
+    pub(crate) fn execute_set_tags(
+        tx: &Connection,
+        id: i64,
+        tags: &[&str],
+    ) -> rusqlite::Result<()> {
+        tx.execute("DELETE FROM substitute_tags WHERE substitute_id = ?1", [id])?;
+        for tag in tags {
+            tx.execute(
+                "INSERT OR IGNORE INTO substitute_tags (substitute_id, tag) VALUES (?1, ?2)",
+                (id, tag),
+            )?;
+        }
+        Ok(())
+    }
+
+    // Inserts new substitutes and refreshes the attributes of existing ones in one
+    // transaction, like `ConflictPolicy::Overwrite` but keeping the stored spelling of each
+    // value. Existing substitutes whose attributes already match count as skipped.
+    pub fn upsert_subs(&mut self, template: &str, entries: &[SubEntry]) -> Result<ImportReport> {
+        with_context("upsert_subs", Some(template), None, || {
+            let tx = self.db.savepoint()?;
+            Self::execute_check_unlocked(&tx, template)?;
+            let mut report = ImportReport::default();
+
+            if Self::execute_insert_template(&tx, template)? {
+                report.templates_created += 1;
+            }
+            let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+
+            for entry in entries {
+                let outcome = match Self::execute_find_entry(&tx, &template_id, entry.value)? {
+                    Some(stored) if Self::execute_update_entry(&tx, &stored, entry, false)? => {
+                        InsertOutcome::Overwritten
+                    }
+                    Some(_) => InsertOutcome::Skipped,
+                    None => {
+                        Self::execute_insert_entry(&tx, &template_id, entry)?;
+                        InsertOutcome::Inserted
+                    }
+                };
+                report.record(outcome);
+            }
+
+            tx.commit()?;
+
+            Ok(report)
+        })
+    }
+
+    // Copies `template` with the weight, tags and metadata of its substitutes into `other`,
+    // reading it in one transaction here and writing it in one transaction there. Values
+    // already in `other` are handled by `policy`; other values there are left alone.
+    pub fn copy_template_to(
+        &self,
+        template: &str,
+        other: &mut TemplateDatabase,
+        policy: ConflictPolicy,
+    ) -> Result<ImportReport> {
+        with_context("copy_template_to", Some(template), None, || {
+            let tx = self.read_transaction()?;
+            let (template_id, name): (i64, String) = tx.query_row(
+                "SELECT id, name FROM templates WHERE name = ?1",
+                [template],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+
+            let mut subs: Vec<(String, i64, Option<String>, Vec<String>)> = Vec::new();
+            {
+                let mut stmt = tx.prepare(
+                    "SELECT id, name, weight, metadata FROM substitutes
+                     WHERE template_id = ?1
+                     ORDER BY LOWER(name) ASC",
+                )?;
+                let mut rows = stmt.query([template_id])?;
+                while let Some(row) = rows.next()? {
+                    let tags = Self::execute_get_tags(&tx, row.get(0)?)?;
+                    subs.push((row.get(1)?, row.get(2)?, row.get(3)?, tags));
+                }
+            }
+            tx.commit()?;
+
+            let tags: Vec<Vec<&str>> = subs
+                .iter()
+                .map(|(_, _, _, tags)| tags.iter().map(|x| x.as_str()).collect())
+                .collect();
+            let entries: Vec<SubEntry> = subs
+                .iter()
+                .zip(&tags)
+                .map(|((value, weight, metadata, _), tags)| SubEntry {
+                    value,
+                    weight: Some(*weight),
+                    tags: Some(tags),
+                    metadata: metadata.as_deref(),
+                })
+                .collect();
+
+            let tx = other.db.savepoint()?;
+            let mut report = ImportReport::default();
+            Self::execute_import_entries(&tx, &name, &entries, policy, &mut report)?;
+            tx.commit()?;
+
+            Ok(report)
+        })
+    }
+
+    // Applies exported items in one transaction, the counterpart of `export_chunk`.
+    pub fn import_items(
+        &mut self,
+        items: &[ExportItem],
+        policy: ConflictPolicy,
+    ) -> Result<ImportReport> {
+        with_context("import_items", None, None, || {
+            let tx = self.db.savepoint()?;
+            let mut report = ImportReport::default();
+
+            for item in items {
+                match item {
+                    ExportItem::Template(template) => {
+                        if Self::execute_insert_template(&tx, template)? {
+                            report.templates_created += 1;
+                        }
+                    }
+                    ExportItem::Substitute { template, name } => {
+                        let entry = SubEntry::new(name);
+                        Self::execute_import_entries(&tx, template, &[entry], policy, &mut report)?;
+                    }
+                }
+            }
+
+            tx.commit()?;
+
+            Ok(report)
+        })
+    }
+}
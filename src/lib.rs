@@ -1,22 +1,154 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+mod backend;
+#[cfg(feature = "sqlite")]
+mod batch;
+#[cfg(feature = "sqlite")]
+mod cache;
+#[cfg(feature = "sqlite")]
+mod canonical;
+#[cfg(feature = "sqlite")]
+mod compact;
+#[cfg(feature = "sqlite")]
+mod dedup;
+mod embedded;
+#[cfg(feature = "sqlite")]
+mod error;
+#[cfg(feature = "sqlite")]
+mod export;
+#[cfg(feature = "sqlite")]
+mod full_export;
+#[cfg(feature = "sqlite")]
+mod health;
+#[cfg(feature = "sqlite")]
+mod import;
+#[cfg(feature = "sqlite")]
+mod lock;
+#[cfg(feature = "sqlite")]
+mod merge;
+#[cfg(feature = "sqlite")]
+mod packs;
+#[cfg(feature = "sqlite")]
+mod patterns;
+#[cfg(feature = "sqlite")]
+mod pin;
+#[cfg(feature = "sqlite")]
+mod predicate;
+#[cfg(feature = "sqlite")]
+mod query;
+#[cfg(feature = "sqlite")]
+mod render;
+mod rng;
+#[cfg(feature = "sqlite")]
+mod snapshot;
+mod source;
+#[cfg(feature = "sqlite")]
+mod stats;
+#[cfg(feature = "sqlite")]
+mod template;
+#[cfg(feature = "sqlite")]
+mod timeout;
+#[cfg(feature = "sqlite")]
+mod usage;
+#[cfg(feature = "sqlite")]
+mod weights;
+#[cfg(feature = "sqlite")]
+mod wordlist;
+
+pub use backend::{CachedBackend, MemoryBackend, StorageBackend};
+#[cfg(feature = "sqlite")]
+pub use cache::TemplateCache;
+#[cfg(feature = "sqlite")]
+pub use canonical::CanonicalSub;
+#[cfg(feature = "sqlite")]
+pub use compact::CompactReport;
+#[cfg(feature = "sqlite")]
+pub use dedup::InsertFilter;
+pub use embedded::StaticTemplates;
+#[cfg(feature = "sqlite")]
+use error::with_context;
+#[cfg(feature = "sqlite")]
+pub use error::{Error, Result};
+#[cfg(feature = "sqlite")]
+pub use export::{ExportChunk, ExportCursor, ExportItem};
+#[cfg(feature = "sqlite")]
+pub use health::HealthStatus;
+#[cfg(feature = "sqlite")]
+pub use import::{ConflictPolicy, ImportReport, SubEntry};
+#[cfg(feature = "sqlite")]
+pub use merge::{MergeCollision, MergePlan};
+#[cfg(feature = "sqlite")]
+pub use packs::Pack;
+#[cfg(feature = "sqlite")]
+pub use patterns::{RemoveReport, RenameReport};
+#[cfg(feature = "sqlite")]
+pub use predicate::SubPredicate;
+#[cfg(feature = "sqlite")]
+pub use query::{Order, Query};
+pub use rng::Rng;
+#[cfg(feature = "sqlite")]
 pub use rusqlite;
-use rusqlite::{Connection, Transaction};
-
-const DATABASE_VERSION: i32 = 1;
-
+#[cfg(feature = "sqlite")]
+use rusqlite::{Connection, OptionalExtension};
+pub use source::TemplateSource;
+#[cfg(feature = "sqlite")]
+pub use stats::LengthStats;
+#[cfg(feature = "sqlite")]
+use std::collections::HashMap;
+#[cfg(feature = "sqlite")]
+pub use template::{Template, TemplateEntry};
+#[cfg(feature = "sqlite")]
+pub use usage::{PruneReport, SubUsage};
+#[cfg(feature = "sqlite")]
+use weights::WeightedSubs;
+
+#[cfg(feature = "sqlite")]
+const DATABASE_VERSION: i32 = 8;
+
+#[cfg(feature = "sqlite")]
 #[derive(Debug)]
 pub struct TemplateDatabase {
     db: Connection,
+    track_usage: bool,
+    pins: HashMap<String, String>,
+}
+
+pub type UpdatedValues<'a> = alloc::vec::Vec<&'a str>;
+
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SubsDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub updated: Vec<String>,
 }
 
-pub type UpdatedValues<'a> = Vec<&'a str>;
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubDetail {
+    pub id: i64,
+    pub template: String,
+    pub value: String,
+    pub weight: i64,
+    pub tags: Vec<String>,
+    pub metadata: Option<String>,
+    // Usage counters and timestamps, as in `SubUsage`.
+    pub use_count: u64,
+    pub last_used: Option<i64>,
+    pub created_at: Option<i64>,
+}
 
+#[cfg(feature = "sqlite")]
 impl TemplateDatabase {
     fn create_tables(db: &Connection) -> rusqlite::Result<()> {
         db.execute(
             "
             CREATE TABLE IF NOT EXISTS templates (
             id INTEGER PRIMARY KEY,
-            name TEXT NOT NULL UNIQUE COLLATE NOCASE
+            name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+            locked INTEGER NOT NULL DEFAULT 0
         )",
             [],
         )?;
@@ -27,11 +159,151 @@ impl TemplateDatabase {
             id INTEGER PRIMARY KEY,
             name TEXT NOT NULL COLLATE NOCASE,
             template_id INTEGER NOT NULL REFERENCES templates(id),
+            weight INTEGER NOT NULL DEFAULT 1,
+            metadata TEXT,
+            use_count INTEGER NOT NULL DEFAULT 0,
+            last_used INTEGER,
+            created_at INTEGER,
             UNIQUE(name, template_id)
         )",
             [],
         )?;
 
+        Self::create_tag_table(db)?;
+        Self::create_pattern_table(db)?;
+        Self::create_pack_tables(db)?;
+        Self::create_created_at_trigger(db)?;
+        Self::create_usage_tracking_table(db)?;
+
+        Ok(())
+    }
+
+    // Holds a single row with the time usage tracking was turned on while it stays on, so
+    // pruning knows how long a zero `use_count` has actually been counted for.
+    fn create_usage_tracking_table(db: &Connection) -> rusqlite::Result<()> {
+        db.execute(
+            "
+            CREATE TABLE IF NOT EXISTS usage_tracking (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            started_at INTEGER NOT NULL
+        )",
+            [],
+        )?;
+
+        Ok(())
+    }
+
+    // Stamps new substitutes without touching every insert statement. Rows from before
+    // schema version 7 keep a NULL `created_at` and count as stored when tracking started.
+    fn create_created_at_trigger(db: &Connection) -> rusqlite::Result<()> {
+        db.execute(
+            "
+            CREATE TRIGGER IF NOT EXISTS set_substitute_created_at
+            AFTER INSERT ON substitutes
+            WHEN NEW.created_at IS NULL
+            BEGIN
+                UPDATE substitutes
+                SET created_at = CAST(strftime('%s', 'now') AS INTEGER)
+                WHERE id = NEW.id;
+            END",
+            [],
+        )?;
+
+        Ok(())
+    }
+
+    // Packs record the rows they added, so triggers drop those records when the rows go
+    // away by other means and a reused rowid is never mistaken for pack content.
+    fn create_pack_tables(db: &Connection) -> rusqlite::Result<()> {
+        db.execute(
+            "
+            CREATE TABLE IF NOT EXISTS packs (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+            version TEXT NOT NULL
+        )",
+            [],
+        )?;
+
+        db.execute(
+            "
+            CREATE TABLE IF NOT EXISTS pack_contents (
+            pack_id INTEGER NOT NULL REFERENCES packs(id),
+            kind TEXT NOT NULL,
+            item_id INTEGER NOT NULL,
+            UNIQUE(kind, item_id)
+        )",
+            [],
+        )?;
+
+        db.execute(
+            "
+            CREATE TABLE IF NOT EXISTS pack_dependencies (
+            pack_id INTEGER NOT NULL REFERENCES packs(id),
+            dependency TEXT NOT NULL COLLATE NOCASE,
+            requirement TEXT NOT NULL,
+            UNIQUE(pack_id, dependency)
+        )",
+            [],
+        )?;
+
+        for (table, kind) in [
+            ("templates", "template"),
+            ("substitutes", "substitute"),
+            ("patterns", "pattern"),
+        ] {
+            db.execute(
+                &format!(
+                    "
+                    CREATE TRIGGER IF NOT EXISTS delete_{}_pack_contents
+                    BEFORE DELETE ON {}
+                    BEGIN
+                        DELETE FROM pack_contents WHERE kind = '{}' AND item_id = OLD.id;
+                    END",
+                    table, table, kind
+                ),
+                [],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn create_pattern_table(db: &Connection) -> rusqlite::Result<()> {
+        db.execute(
+            "
+            CREATE TABLE IF NOT EXISTS patterns (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+            pattern TEXT NOT NULL
+        )",
+            [],
+        )?;
+
+        Ok(())
+    }
+
+    fn create_tag_table(db: &Connection) -> rusqlite::Result<()> {
+        db.execute(
+            "
+            CREATE TABLE IF NOT EXISTS substitute_tags (
+            substitute_id INTEGER NOT NULL REFERENCES substitutes(id),
+            tag TEXT NOT NULL COLLATE NOCASE,
+            UNIQUE(substitute_id, tag)
+        )",
+            [],
+        )?;
+
+        db.execute(
+            "
+            CREATE TRIGGER IF NOT EXISTS delete_substitute_tags
+            BEFORE DELETE ON substitutes
+            BEGIN
+                DELETE FROM substitute_tags WHERE substitute_id = OLD.id;
+            END",
+            [],
+        )?;
+
         Ok(())
     }
 
@@ -42,13 +314,33 @@ impl TemplateDatabase {
         if stmt.query([])?.next()?.is_some() {
             let version = Self::get_schema_version(db)?;
 
-            match version {
-                0 => Self::upgrade_to_version_1(db)?,
-                _ => {}
+            if version == 0 {
+                Self::upgrade_to_version_1(db)?;
+            }
+            if version < 2 {
+                Self::upgrade_to_version_2(db)?;
+            }
+            if version < 3 {
+                Self::upgrade_to_version_3(db)?;
+            }
+            if version < 4 {
+                Self::upgrade_to_version_4(db)?;
+            }
+            if version < 5 {
+                Self::upgrade_to_version_5(db)?;
+            }
+            if version < 6 {
+                Self::upgrade_to_version_6(db)?;
+            }
+            if version < 7 {
+                Self::upgrade_to_version_7(db)?;
+            }
+            if version < 8 {
+                Self::upgrade_to_version_8(db)?;
             }
         } else {
             Self::set_schema_version(db, DATABASE_VERSION)?;
-            Self::create_tables(&db)?;
+            Self::create_tables(db)?;
         }
         Ok(())
     }
@@ -127,61 +419,165 @@ impl TemplateDatabase {
         Ok(())
     }
 
+    fn has_column(db: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+        let mut stmt = db.prepare(&format!("PRAGMA table_info({})", table))?;
+        let columns = stmt.query_map([], |row| row.get::<_, String>(1))?;
+        for name in columns {
+            if name? == column {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Upgrading from version 0 rebuilds the tables in their current shape, so every step
+    // here has to tolerate already being applied.
+    fn upgrade_to_version_2(db: &Connection) -> rusqlite::Result<()> {
+        if !Self::has_column(db, "substitutes", "weight")? {
+            db.execute(
+                "ALTER TABLE substitutes ADD COLUMN weight INTEGER NOT NULL DEFAULT 1",
+                [],
+            )?;
+        }
+        if !Self::has_column(db, "substitutes", "metadata")? {
+            db.execute("ALTER TABLE substitutes ADD COLUMN metadata TEXT", [])?;
+        }
+        Self::create_tag_table(db)?;
+        Self::set_schema_version(db, 2)?;
+        Ok(())
+    }
+
+    fn upgrade_to_version_3(db: &Connection) -> rusqlite::Result<()> {
+        Self::create_pattern_table(db)?;
+        Self::set_schema_version(db, 3)?;
+        Ok(())
+    }
+
+    fn upgrade_to_version_4(db: &Connection) -> rusqlite::Result<()> {
+        if !Self::has_column(db, "templates", "locked")? {
+            db.execute(
+                "ALTER TABLE templates ADD COLUMN locked INTEGER NOT NULL DEFAULT 0",
+                [],
+            )?;
+        }
+        Self::set_schema_version(db, 4)?;
+        Ok(())
+    }
+
+    fn upgrade_to_version_5(db: &Connection) -> rusqlite::Result<()> {
+        Self::create_pack_tables(db)?;
+        Self::set_schema_version(db, 5)?;
+        Ok(())
+    }
+
+    fn upgrade_to_version_6(db: &Connection) -> rusqlite::Result<()> {
+        Self::create_pack_tables(db)?;
+        Self::set_schema_version(db, 6)?;
+        Ok(())
+    }
+
+    fn upgrade_to_version_7(db: &Connection) -> rusqlite::Result<()> {
+        if !Self::has_column(db, "substitutes", "use_count")? {
+            db.execute(
+                "ALTER TABLE substitutes ADD COLUMN use_count INTEGER NOT NULL DEFAULT 0",
+                [],
+            )?;
+        }
+        if !Self::has_column(db, "substitutes", "last_used")? {
+            db.execute("ALTER TABLE substitutes ADD COLUMN last_used INTEGER", [])?;
+        }
+        if !Self::has_column(db, "substitutes", "created_at")? {
+            db.execute("ALTER TABLE substitutes ADD COLUMN created_at INTEGER", [])?;
+        }
+        Self::create_created_at_trigger(db)?;
+        Self::set_schema_version(db, 7)?;
+        Ok(())
+    }
+
+    // Patterns stored before version 8 predate optional segments, alternations and escapes,
+    // so their brackets, parentheses, bars and backslashes are escaped to stay literal.
+    fn upgrade_to_version_8(db: &Connection) -> rusqlite::Result<()> {
+        Self::create_usage_tracking_table(db)?;
+
+        let patterns: Vec<(i64, String)> = {
+            let mut stmt = db.prepare("SELECT id, pattern FROM patterns")?;
+            let patterns = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            patterns.collect::<rusqlite::Result<_>>()?
+        };
+        for (id, pattern) in patterns {
+            let escaped = render::escape(&pattern);
+            if escaped != pattern {
+                db.execute(
+                    "UPDATE patterns SET pattern = ?1 WHERE id = ?2",
+                    (escaped, id),
+                )?;
+            }
+        }
+
+        Self::set_schema_version(db, 8)?;
+        Ok(())
+    }
+
     pub fn from_path(path: &str) -> rusqlite::Result<TemplateDatabase> {
         let db = Connection::open(path)?;
 
         Self::initialize_db(&db)?;
-
-        Ok(TemplateDatabase { db })
+        stats::create_word_count_function(&db)?;
+        let track_usage = Self::execute_usage_tracked(&db)?;
+
+        Ok(TemplateDatabase {
+            db,
+            track_usage,
+            pins: HashMap::new(),
+        })
     }
 
     fn find_template_id_with_transaction(
-        tx: &Transaction,
+        tx: &Connection,
         template: &str,
     ) -> rusqlite::Result<String> {
         let mut stmt = tx.prepare("SELECT id FROM templates WHERE name = ?1")?;
-        let template_id: i64 = stmt.query_row(&[template], |row| row.get(0))?;
+        let template_id: i64 = stmt.query_row([template], |row| row.get(0))?;
         Ok(template_id.to_string())
     }
 
-    pub fn insert_sub<'a>(
-        &mut self,
-        template: &'a str,
-        substitute: &'a str,
-    ) -> rusqlite::Result<bool> {
-        let tx = self.db.transaction()?;
-        Self::execute_insert_template(&tx, template)?;
-        let template_id = Self::find_template_id_with_transaction(&tx, template)?;
-        let result = tx.execute(
-            "INSERT OR IGNORE INTO substitutes (name, template_id) VALUES (?1, ?2)",
-            [substitute.to_string(), template_id.to_string()],
-        )?;
+    pub fn insert_sub<'a>(&mut self, template: &'a str, substitute: &'a str) -> Result<bool> {
+        with_context("insert_sub", Some(template), Some(substitute), || {
+            let tx = self.db.savepoint()?;
+            Self::execute_check_unlocked(&tx, template)?;
+            Self::execute_insert_template(&tx, template)?;
+            let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+            let result = tx.execute(
+                "INSERT OR IGNORE INTO substitutes (name, template_id) VALUES (?1, ?2)",
+                [substitute.to_string(), template_id.to_string()],
+            )?;
 
-        tx.commit()?;
+            tx.commit()?;
 
-        Ok(result > 0)
+            Ok(result > 0)
+        })
     }
 
-    fn execute_insert_template(tx: &Transaction, template: &str) -> rusqlite::Result<()> {
-        tx.execute(
+    fn execute_insert_template(tx: &Connection, template: &str) -> rusqlite::Result<bool> {
+        let result = tx.execute(
             "INSERT OR IGNORE INTO templates (name) VALUES (?1)",
-            &[template],
+            [template],
         )?;
-        Ok(())
+        Ok(result > 0)
     }
 
     fn execute_insert_subs<'a>(
-        tx: &Transaction,
+        tx: &Connection,
         template: &str,
         substitutes: &[&'a str],
     ) -> rusqlite::Result<UpdatedValues<'a>> {
-        let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+        let template_id = Self::find_template_id_with_transaction(tx, template)?;
         let mut inserted_subs = UpdatedValues::new();
 
         for sub in substitutes {
             let result = tx.execute(
                 "INSERT OR IGNORE INTO substitutes (name, template_id) VALUES (?1, ?2)",
-                &[*sub, &template_id],
+                [*sub, &template_id],
             )?;
             if result > 0 {
                 inserted_subs.push(*sub);
@@ -195,96 +591,175 @@ impl TemplateDatabase {
         &mut self,
         template: &'a str,
         substitutes: Option<&[&'a str]>,
-    ) -> rusqlite::Result<UpdatedValues<'a>> {
-        let mut change_log = UpdatedValues::new();
+    ) -> Result<UpdatedValues<'a>> {
+        with_context("insert_subs", Some(template), None, || {
+            let mut change_log = UpdatedValues::new();
 
-        let tx = self.db.transaction()?;
+            let tx = self.db.savepoint()?;
+            Self::execute_check_unlocked(&tx, template)?;
 
-        Self::execute_insert_template(&tx, template)?;
+            Self::execute_insert_template(&tx, template)?;
 
-        if let Some(subs) = substitutes {
-            change_log = Self::execute_insert_subs(&tx, template, subs)?;
-        }
+            if let Some(subs) = substitutes {
+                change_log = Self::execute_insert_subs(&tx, template, subs)?;
+            }
 
-        tx.commit()?;
+            tx.commit()?;
 
-        Ok(change_log)
+            Ok(change_log)
+        })
     }
 
-    pub fn remove_template(&mut self, template: &str) -> rusqlite::Result<bool> {
-        let tx = self.db.transaction()?;
-        let template_id = Self::find_template_id_with_transaction(&tx, template)?;
-
-        tx.execute(
-            "DELETE FROM substitutes WHERE template_id = ?1",
-            [&template_id],
+    fn execute_insert_sub_returning_id(
+        tx: &Connection,
+        template_id: &str,
+        substitute: &str,
+    ) -> rusqlite::Result<i64> {
+        let result = tx.execute(
+            "INSERT OR IGNORE INTO substitutes (name, template_id) VALUES (?1, ?2)",
+            [substitute, template_id],
         )?;
 
-        let result = tx.execute("DELETE FROM templates WHERE id = ?1", [&template_id])?;
+        if result > 0 {
+            return Ok(tx.last_insert_rowid());
+        }
 
-        tx.commit()?;
+        tx.query_row(
+            "SELECT id FROM substitutes WHERE template_id = ?1 AND name = ?2",
+            [template_id, substitute],
+            |row| row.get(0),
+        )
+    }
 
-        Ok(result > 0)
+    // Returns the id of the substitute, whether it was just inserted or already existed.
+    pub fn insert_sub_returning_id(&mut self, template: &str, substitute: &str) -> Result<i64> {
+        with_context(
+            "insert_sub_returning_id",
+            Some(template),
+            Some(substitute),
+            || {
+                let tx = self.db.savepoint()?;
+                Self::execute_check_unlocked(&tx, template)?;
+                Self::execute_insert_template(&tx, template)?;
+                let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+                let id = Self::execute_insert_sub_returning_id(&tx, &template_id, substitute)?;
+
+                tx.commit()?;
+
+                Ok(id)
+            },
+        )
     }
 
-    pub fn remove_sub<'a>(
+    pub fn insert_subs_returning_ids(
         &mut self,
-        template: &'a str,
-        substitute: &'a str,
-    ) -> rusqlite::Result<bool> {
-        let tx = self.db.transaction()?;
-        let template_id = Self::find_template_id_with_transaction(&tx, template)?;
-
-        let result = tx.execute(
-            "DELETE FROM substitutes WHERE template_id = ?1 AND name = ?2",
-            &[&template_id, substitute],
-        )?;
+        template: &str,
+        substitutes: &[&str],
+    ) -> Result<Vec<i64>> {
+        with_context("insert_subs_returning_ids", Some(template), None, || {
+            let tx = self.db.savepoint()?;
+            Self::execute_check_unlocked(&tx, template)?;
+            Self::execute_insert_template(&tx, template)?;
+            let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+
+            let mut ids = Vec::with_capacity(substitutes.len());
+            for sub in substitutes {
+                ids.push(Self::execute_insert_sub_returning_id(
+                    &tx,
+                    &template_id,
+                    sub,
+                )?);
+            }
 
-        tx.commit()?;
+            tx.commit()?;
 
-        Ok(result > 0)
+            Ok(ids)
+        })
     }
 
-    pub fn remove_subs<'a>(
-        &mut self,
-        template: &'a str,
-        substitutes: &[&'a str],
-    ) -> rusqlite::Result<UpdatedValues<'a>> {
-        let tx = self.db.transaction()?;
-        let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+    pub fn remove_template(&mut self, template: &str) -> Result<bool> {
+        with_context("remove_template", Some(template), None, || {
+            let tx = self.db.savepoint()?;
+            Self::execute_check_unlocked(&tx, template)?;
+            let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+
+            tx.execute(
+                "DELETE FROM substitutes WHERE template_id = ?1",
+                [&template_id],
+            )?;
 
-        let mut removed_subs = UpdatedValues::new();
+            let result = tx.execute("DELETE FROM templates WHERE id = ?1", [&template_id])?;
+
+            tx.commit()?;
+
+            Ok(result > 0)
+        })
+    }
+
+    pub fn remove_sub<'a>(&mut self, template: &'a str, substitute: &'a str) -> Result<bool> {
+        with_context("remove_sub", Some(template), Some(substitute), || {
+            let tx = self.db.savepoint()?;
+            Self::execute_check_unlocked(&tx, template)?;
+            let template_id = Self::find_template_id_with_transaction(&tx, template)?;
 
-        for sub in substitutes {
             let result = tx.execute(
                 "DELETE FROM substitutes WHERE template_id = ?1 AND name = ?2",
-                &[&template_id, *sub],
+                [&template_id, substitute],
             )?;
-            if result > 0 {
-                removed_subs.push(*sub);
-            }
-        }
 
-        tx.commit()?;
+            tx.commit()?;
 
-        Ok(removed_subs)
+            Ok(result > 0)
+        })
     }
 
-    pub fn rename_template(
+    pub fn remove_subs<'a>(
         &mut self,
-        old_template: &str,
-        new_template: &str,
-    ) -> rusqlite::Result<bool> {
-        let tx = self.db.transaction()?;
+        template: &str,
+        substitutes: &[&'a str],
+    ) -> Result<UpdatedValues<'a>> {
+        with_context("remove_subs", Some(template), None, || {
+            let tx = self.db.savepoint()?;
+            Self::execute_check_unlocked(&tx, template)?;
+            let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+
+            let mut removed_subs = UpdatedValues::new();
+
+            for sub in substitutes {
+                let result = tx.execute(
+                    "DELETE FROM substitutes WHERE template_id = ?1 AND name = ?2",
+                    [&template_id, *sub],
+                )?;
+                if result > 0 {
+                    removed_subs.push(*sub);
+                }
+            }
 
-        let result = tx.execute(
-            "UPDATE templates SET name = ?1 WHERE name = ?2",
-            &[new_template, old_template],
-        )?;
+            tx.commit()?;
 
-        tx.commit()?;
+            Ok(removed_subs)
+        })
+    }
 
-        Ok(result > 0)
+    pub fn rename_template(&mut self, old_template: &str, new_template: &str) -> Result<bool> {
+        with_context(
+            "rename_template",
+            Some(old_template),
+            Some(new_template),
+            || {
+                let tx = self.db.savepoint()?;
+                Self::execute_check_unlocked(&tx, old_template)?;
+
+                let result = tx.execute(
+                    "UPDATE templates SET name = ?1 WHERE name = ?2",
+                    [new_template, old_template],
+                )?;
+
+                tx.commit()?;
+
+                Ok(result > 0)
+            },
+        )
     }
 
     pub fn rename_substitute(
@@ -292,32 +767,98 @@ impl TemplateDatabase {
         template: &str,
         old_sub: &str,
         new_sub: &str,
-    ) -> rusqlite::Result<bool> {
-        let tx = self.db.transaction()?;
+    ) -> Result<bool> {
+        with_context("rename_substitute", Some(template), Some(old_sub), || {
+            let tx = self.db.savepoint()?;
+            Self::execute_check_unlocked(&tx, template)?;
 
-        let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+            let template_id = Self::find_template_id_with_transaction(&tx, template)?;
 
-        let result = tx.execute(
-            "UPDATE substitutes SET name = ?1 WHERE name = ?2 AND template_id = ?3",
-            &[new_sub, old_sub, &template_id],
-        )?;
+            let result = tx.execute(
+                "UPDATE substitutes SET name = ?1 WHERE name = ?2 AND template_id = ?3",
+                [new_sub, old_sub, &template_id],
+            )?;
 
-        tx.commit()?;
+            tx.commit()?;
 
-        Ok(result > 0)
+            Ok(result > 0)
+        })
     }
 
-    pub fn clear(&self) -> rusqlite::Result<()> {
-        self.db.execute("DELETE FROM substitutes", [])?;
-        self.db.execute("DELETE FROM templates", [])?;
-        Ok(())
+    // Makes `substitutes` the exact contents of the template in one transaction, creating
+    // the template if needed. Values kept across the replace keep their ids; values that only
+    // differ in case are rewritten in place and reported as updated.
+    pub fn replace_subs(&mut self, template: &str, substitutes: &[&str]) -> Result<SubsDiff> {
+        with_context("replace_subs", Some(template), None, || {
+            let tx = self.db.savepoint()?;
+            Self::execute_check_unlocked(&tx, template)?;
+            Self::execute_insert_template(&tx, template)?;
+            let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+
+            let existing: Vec<String> = {
+                let mut stmt = tx.prepare("SELECT name FROM substitutes WHERE template_id = ?1")?;
+                let existing = stmt.query_map([&template_id], |row| row.get(0))?;
+                existing.collect::<rusqlite::Result<_>>()?
+            };
+
+            let mut diff = SubsDiff::default();
+
+            for old in &existing {
+                if !substitutes.iter().any(|x| x.eq_ignore_ascii_case(old)) {
+                    tx.execute(
+                        "DELETE FROM substitutes WHERE template_id = ?1 AND name = ?2",
+                        [&template_id, old],
+                    )?;
+                    diff.removed.push(old.clone());
+                }
+            }
+
+            for new in substitutes {
+                match existing.iter().find(|x| x.eq_ignore_ascii_case(new)) {
+                    Some(old) if old == new => {}
+                    Some(old) => {
+                        tx.execute(
+                            "UPDATE substitutes SET name = ?1 WHERE template_id = ?2 AND name = ?3",
+                            [new, &template_id.as_str(), &old.as_str()],
+                        )?;
+                        diff.updated.push(new.to_string());
+                    }
+                    None => {
+                        let result = tx.execute(
+                            "INSERT OR IGNORE INTO substitutes (name, template_id) VALUES (?1, ?2)",
+                            [*new, &template_id],
+                        )?;
+                        if result > 0 {
+                            diff.added.push(new.to_string());
+                        }
+                    }
+                }
+            }
+
+            tx.commit()?;
+
+            Ok(diff)
+        })
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        with_context("clear", None, None, || {
+            Self::execute_check_none_locked(&self.db)?;
+            self.db.execute("DELETE FROM pack_dependencies", [])?;
+            self.db.execute("DELETE FROM pack_contents", [])?;
+            self.db.execute("DELETE FROM packs", [])?;
+            self.db.execute("DELETE FROM patterns", [])?;
+            self.db.execute("DELETE FROM substitutes", [])?;
+            self.db.execute("DELETE FROM templates", [])?;
+            Ok(())
+        })
     }
 
     fn find_template_id(&self, template: &str) -> rusqlite::Result<String> {
         let mut stmt = self
             .db
             .prepare("SELECT id FROM templates WHERE name = ?1")?;
-        let template_id: i64 = stmt.query_row(&[template], |row| row.get(0))?;
+        let template_id: i64 = stmt.query_row([template], |row| row.get(0))?;
         Ok(template_id.to_string())
     }
 
@@ -332,30 +873,82 @@ impl TemplateDatabase {
 
         let substitutes = stmt.query_map([template_id], |row| row.get(0))?;
 
-        Ok(substitutes
-            .filter(|x| x.is_ok())
-            .map(|x| x.unwrap())
-            .collect())
+        Ok(substitutes.flatten().collect())
     }
 
-    pub fn get_random_subs(&self, template: &str) -> rusqlite::Result<String> {
-        let template_id = self.find_template_id(template)?;
-        let mut stmt = self.db.prepare(
-            "SELECT substitutes.name
+    pub fn get_sub_detail(
+        &self,
+        template: &str,
+        substitute: &str,
+    ) -> rusqlite::Result<Option<SubDetail>> {
+        let mut stmt = self.db.prepare_cached(
+            "SELECT substitutes.id, templates.name, substitutes.name,
+                    substitutes.weight, substitutes.metadata,
+                    (SELECT group_concat(tag, char(31))
+                     FROM substitute_tags
+                     WHERE substitute_id = substitutes.id),
+                    substitutes.use_count, substitutes.last_used, substitutes.created_at
              FROM substitutes
-             WHERE template_id = ?1
-             ORDER BY RANDOM() LIMIT 1;",
+             JOIN templates ON templates.id = substitutes.template_id
+             WHERE templates.name = ?1 AND substitutes.name = ?2;",
         )?;
 
-        let mut rows = stmt.query([template_id])?;
+        stmt.query_row([template, substitute], |row| {
+            let tags: Option<String> = row.get(5)?;
+            let mut tags: Vec<String> = tags
+                .map(|x| x.split('\u{1f}').map(|x| x.to_string()).collect())
+                .unwrap_or_default();
+            tags.sort_by_key(|x| x.to_lowercase());
+
+            Ok(SubDetail {
+                id: row.get(0)?,
+                template: row.get(1)?,
+                value: row.get(2)?,
+                weight: row.get(3)?,
+                tags,
+                metadata: row.get(4)?,
+                use_count: row.get::<_, i64>(6)? as u64,
+                last_used: row.get(7)?,
+                created_at: row.get(8)?,
+            })
+        })
+        .optional()
+    }
+
+    pub fn get_random_subs(&self, template: &str) -> rusqlite::Result<String> {
+        if let Some(pinned) = self.pinned(template) {
+            return Ok(pinned.to_string());
+        }
+        let template_id = self.find_template_id(template)?;
+        let mut rng = Rng::from_db(&self.db)?;
+        self.pick_weighted(template_id, &mut rng)
+    }
+
+    // Picks up to `count` distinct substitutes for each template, all read in one transaction.
+    pub fn get_random_picks(&self, picks: &[(&str, usize)]) -> rusqlite::Result<Vec<Vec<String>>> {
+        let tx = self.read_transaction()?;
+        let mut rng = Rng::from_db(&tx)?;
+        let mut results = Vec::with_capacity(picks.len());
 
-        match rows.next()? {
-            Some(row) => {
-                let sub: String = row.get(0)?;
-                return Ok(sub);
+        for (template, count) in picks {
+            if let Some(pinned) = self.pinned(template) {
+                results.push(vec![pinned.to_string(); (*count).min(1)]);
+                continue;
             }
-            _ => Ok("".to_string()),
+            let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+            let subs = WeightedSubs::load(&tx, &template_id)?;
+            let picked = subs.pick_distinct(*count, &mut rng);
+            if self.track_usage {
+                for sub in &picked {
+                    Self::execute_record_use(&tx, &template_id, sub, 1)?;
+                }
+            }
+            results.push(picked);
         }
+
+        tx.commit()?;
+
+        Ok(results)
     }
 
     pub fn get_templates(&self) -> rusqlite::Result<Vec<String>> {
@@ -367,15 +960,41 @@ impl TemplateDatabase {
 
         let templates = stmt.query_map([], |row| row.get(0))?;
 
-        Ok(templates
-            .filter(|x| x.is_ok())
-            .map(|x| x.unwrap())
-            .collect())
+        Ok(templates.flatten().collect())
+    }
+
+    // Patterns containing `*`, `?` or `[` are matched as globs, anything else as a prefix.
+    // Both ignore ASCII case, like template names themselves.
+    pub fn search_templates(&self, pattern: &str) -> rusqlite::Result<Vec<String>> {
+        let (condition, pattern) = if pattern.contains(['*', '?', '[']) {
+            (
+                "LOWER(templates.name) GLOB ?1",
+                pattern.to_ascii_lowercase(),
+            )
+        } else {
+            (
+                "templates.name LIKE ?1 ESCAPE '\\'",
+                format!("{}%", query::escape_like(pattern)),
+            )
+        };
+
+        let mut stmt = self.db.prepare_cached(&format!(
+            "SELECT templates.name
+             FROM templates
+             WHERE {}
+             ORDER BY LOWER(templates.name) ASC;",
+            condition
+        ))?;
+
+        let templates = stmt.query_map([pattern], |row| row.get(0))?;
+
+        templates.collect()
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "sqlite"))]
 mod tests {
+    use std::time::Duration;
     use std::vec;
 
     use super::*;
@@ -504,7 +1123,7 @@ mod tests {
 
         match db.remove_template("noun") {
             Ok(_) => {}
-            Err(rusqlite::Error::QueryReturnedNoRows) => {
+            Err(err) if matches!(err.sqlite(), Some(rusqlite::Error::QueryReturnedNoRows)) => {
                 dbg!("Ignoring query returned no rows error...");
             }
             Err(err) => {
@@ -555,4 +1174,2283 @@ mod tests {
 
         assert_eq!(db.get_subs("noun").unwrap(), &["example", "example2"]);
     }
+
+    #[test]
+    fn render_pattern() {
+        let mut db = TemplateDatabase::from_path("test10.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("noun", Some(NOUNS)).unwrap();
+        db.insert_subs("verb", Some(&["run"])).unwrap();
+
+        let line = db.render("the {noun} can {verb} {").unwrap();
+        let noun = line
+            .strip_prefix("the ")
+            .and_then(|x| x.strip_suffix(" can run {"))
+            .unwrap();
+
+        assert!(NOUNS.contains(&noun));
+        assert!(db.render("{missing}").is_err());
+    }
+
+    #[test]
+    fn generate_corpus_lines() {
+        let mut db = TemplateDatabase::from_path("test11.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("adj", Some(ADJECTIVES)).unwrap();
+        db.insert_subs("noun", Some(NOUNS)).unwrap();
+
+        let mut output = Vec::new();
+        db.generate_corpus("{adj} {noun}", 100, &mut output)
+            .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+
+        assert_eq!(lines.len(), 100);
+        for line in lines {
+            assert!(ADJECTIVES.iter().any(|adj| line.starts_with(adj)));
+            assert!(NOUNS.iter().any(|noun| line.ends_with(noun)));
+        }
+    }
+
+    #[test]
+    fn generate_unique_outputs() {
+        let mut db = TemplateDatabase::from_path("test12.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("adj", Some(&["big", "small"])).unwrap();
+        db.insert_subs("noun", Some(&["cat", "dog", "ape"]))
+            .unwrap();
+
+        let mut outputs = db.generate_unique("{adj} {noun}", 6).unwrap();
+        outputs.sort();
+
+        assert_eq!(
+            outputs,
+            vec![
+                "big ape",
+                "big cat",
+                "big dog",
+                "small ape",
+                "small cat",
+                "small dog"
+            ]
+        );
+
+        match db.generate_unique("{adj} {noun}", 7) {
+            Err(Error::NotEnoughUniqueOutputs { requested, found }) => {
+                assert_eq!((requested, found), (7, 6));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn generate_corpus_in_parallel() {
+        let mut db = TemplateDatabase::from_path("test13.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("verb", Some(VERBS)).unwrap();
+
+        let mut output = Vec::new();
+        db.generate_corpus_parallel("to {verb}", 25_001, &mut output)
+            .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+
+        assert_eq!(output.lines().count(), 25_001);
+        for line in output.lines() {
+            assert!(VERBS.contains(&line.strip_prefix("to ").unwrap()));
+        }
+    }
+
+    #[test]
+    fn cache_serves_and_refreshes() {
+        let mut db = TemplateDatabase::from_path("test14.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("noun", Some(NOUNS)).unwrap();
+        db.insert_subs("verb", Some(VERBS)).unwrap();
+
+        let mut cache = TemplateCache::load_selected(&db, &["NOUN"]).unwrap();
+
+        assert_eq!(cache.get_templates(), vec!["noun"]);
+        assert_eq!(
+            cache.get_subs("noun").unwrap(),
+            db.get_subs("noun").unwrap()
+        );
+        assert!(NOUNS.contains(&cache.get_random_sub("noun").unwrap()));
+        assert!(cache.get_subs("verb").is_none());
+        assert!(!cache.is_stale(&db).unwrap());
+
+        db.insert_sub("noun", "zebra").unwrap();
+
+        assert!(cache.refresh_if_stale(&db).unwrap());
+        assert!(cache
+            .get_subs("noun")
+            .unwrap()
+            .contains(&"zebra".to_string()));
+        assert!(!cache.refresh_if_stale(&db).unwrap());
+
+        let empty = TemplateCache::load_selected(&db, &[]).unwrap();
+        assert!(empty.get_templates().is_empty());
+    }
+
+    #[test]
+    fn export_in_resumable_chunks() {
+        let mut db = TemplateDatabase::from_path("test15.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("noun", Some(NOUNS)).unwrap();
+        db.insert_subs("verb", Some(VERBS)).unwrap();
+        db.insert_subs("empty", None).unwrap();
+
+        let mut items = Vec::new();
+        let mut token: Option<String> = None;
+
+        loop {
+            let cursor = token
+                .as_deref()
+                .map(|x| ExportCursor::from_token(x).unwrap());
+            let chunk = db.export_chunk(cursor.as_ref(), 5).unwrap();
+            assert!(chunk.items.len() <= 5);
+            items.extend(chunk.items);
+            match chunk.next {
+                Some(next) => token = Some(next.to_token()),
+                None => break,
+            }
+        }
+
+        assert_eq!(items.len(), 3 + NOUNS.len() + VERBS.len());
+        assert!(items.contains(&ExportItem::Template("empty".to_string())));
+        assert!(items.contains(&ExportItem::Substitute {
+            template: "verb".to_string(),
+            name: "jump".to_string(),
+        }));
+        assert!(ExportCursor::from_token("x:1").is_none());
+        assert!(matches!(db.export_chunk(None, 0), Err(Error::ZeroLimit)));
+    }
+
+    #[test]
+    fn insert_with_conflict_policy() {
+        let mut db = TemplateDatabase::from_path("test16.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("noun", Some(&["cat", "dog"])).unwrap();
+
+        let skipped = db
+            .insert_subs_with_policy("noun", &["CAT", "ape"], ConflictPolicy::Skip)
+            .unwrap();
+        assert_eq!(skipped, vec!["ape"]);
+        assert_eq!(db.get_subs("noun").unwrap(), vec!["ape", "cat", "dog"]);
+
+        let overwritten = db
+            .insert_subs_with_policy("noun", &["CAT", "dog"], ConflictPolicy::Overwrite)
+            .unwrap();
+        assert_eq!(overwritten, vec!["CAT"]);
+        assert_eq!(db.get_subs("noun").unwrap(), vec!["ape", "CAT", "dog"]);
+
+        match db.insert_subs_with_policy("noun", &["bed", "dog"], ConflictPolicy::Error) {
+            Err(Error::Conflict { template, value }) => {
+                assert_eq!((template.as_str(), value.as_str()), ("noun", "dog"));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(!db.get_subs("noun").unwrap().contains(&"bed".to_string()));
+
+        let report = db
+            .import_items(
+                &[
+                    ExportItem::Template("verb".to_string()),
+                    ExportItem::Substitute {
+                        template: "noun".to_string(),
+                        name: "ape".to_string(),
+                    },
+                    ExportItem::Substitute {
+                        template: "verb".to_string(),
+                        name: "run".to_string(),
+                    },
+                ],
+                ConflictPolicy::Skip,
+            )
+            .unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                templates_created: 1,
+                inserted: 1,
+                overwritten: 0,
+                skipped: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn insert_returning_ids() {
+        let mut db = TemplateDatabase::from_path("test17.db").unwrap();
+
+        db.clear().unwrap();
+
+        let cat = db.insert_sub_returning_id("noun", "cat").unwrap();
+        let ids = db
+            .insert_subs_returning_ids("noun", &["dog", "CAT", "ape"])
+            .unwrap();
+
+        assert_eq!(ids.len(), 3);
+        assert_eq!(ids[1], cat);
+        assert_ne!(ids[0], ids[2]);
+        assert_eq!(db.insert_sub_returning_id("noun", "dog").unwrap(), ids[0]);
+    }
+
+    #[test]
+    fn template_handle() {
+        let mut db = TemplateDatabase::from_path("test18.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("noun", Some(&["cat", "dog"])).unwrap();
+
+        let mut noun = db.template("NOUN").unwrap();
+
+        assert_eq!(noun.name(), "noun");
+        assert!(noun.add("ape").unwrap());
+        assert!(!noun.add("Cat").unwrap());
+        assert!(noun.remove("dog").unwrap());
+        assert_eq!(noun.subs().unwrap(), vec!["ape", "cat"]);
+        assert_eq!(noun.len().unwrap(), 2);
+        assert!(["ape", "cat"].contains(&noun.random().unwrap().as_str()));
+        assert!(noun.rename("animal").unwrap());
+        assert_eq!(noun.name(), "animal");
+        assert!(noun.add("bat").unwrap());
+
+        assert_eq!(db.get_templates().unwrap(), vec!["animal"]);
+        assert_eq!(db.get_subs("animal").unwrap(), vec!["ape", "bat", "cat"]);
+        assert!(db.template("noun").is_err());
+    }
+
+    #[test]
+    fn template_entry_or_create() {
+        let mut db = TemplateDatabase::from_path("test19.db").unwrap();
+
+        db.clear().unwrap();
+
+        assert!(db.template_entry("verb").get().unwrap().is_none());
+
+        let added = db
+            .template_entry("verb")
+            .or_create()
+            .unwrap()
+            .add_all(&["run", "jump"])
+            .unwrap();
+        assert_eq!(added, vec!["run", "jump"]);
+
+        let added = db
+            .template_entry("VERB")
+            .or_create()
+            .unwrap()
+            .add_all(&["run", "hide"])
+            .unwrap();
+        assert_eq!(added, vec!["hide"]);
+
+        assert_eq!(db.get_templates().unwrap(), vec!["verb"]);
+        assert_eq!(
+            db.template_entry("verb")
+                .get()
+                .unwrap()
+                .unwrap()
+                .len()
+                .unwrap(),
+            3
+        );
+    }
+
+    #[test]
+    fn query_builder_filters() {
+        let mut db = TemplateDatabase::from_path("test20.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("noun", Some(NOUNS)).unwrap();
+        db.insert_subs("verb", Some(VERBS)).unwrap();
+        db.insert_subs("odd", Some(&["100%", "50 percent"]))
+            .unwrap();
+
+        let nouns = db.query().template("noun").contains("an").fetch().unwrap();
+        assert_eq!(nouns, vec!["man", "woman"]);
+
+        let recent = db
+            .query()
+            .template("NOUN")
+            .template("verb")
+            .order(Order::Recent)
+            .limit(2)
+            .fetch()
+            .unwrap();
+        assert_eq!(recent, vec!["slide", "find"]);
+
+        assert_eq!(db.query().contains("%").fetch().unwrap(), vec!["100%"]);
+        assert_eq!(
+            db.query().template("verb").offset(10).count().unwrap(),
+            VERBS.len()
+        );
+        assert!(db.query().template("missing").fetch().unwrap().is_empty());
+
+        db.upsert_subs(
+            "pet",
+            &[
+                SubEntry::new("cat").weight(3).tags(&["animal", "small"]),
+                SubEntry::new("horse").weight(5).tags(&["Animal"]),
+                SubEntry::new("rock").weight(9),
+                SubEntry::new("ant").weight(1).tags(&["animal", "small"]),
+            ],
+        )
+        .unwrap();
+        let pets = db.query().tagged("ANIMAL").min_weight(2).fetch().unwrap();
+        assert_eq!(pets, vec!["cat", "horse"]);
+        let small = db
+            .query()
+            .template("pet")
+            .tagged("animal")
+            .tagged("small")
+            .fetch()
+            .unwrap();
+        assert_eq!(small, vec!["ant", "cat"]);
+        assert_eq!(db.query().template("pet").min_weight(5).count().unwrap(), 2);
+    }
+
+    #[test]
+    fn raw_read_only_queries() {
+        let mut db = TemplateDatabase::from_path("test21.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("noun", Some(NOUNS)).unwrap();
+        db.insert_subs("verb", Some(VERBS)).unwrap();
+
+        let counts = db
+            .query_rows(
+                "SELECT templates.name, COUNT(*)
+                 FROM templates
+                 JOIN substitutes ON substitutes.template_id = templates.id
+                 WHERE LENGTH(substitutes.name) > ?1
+                 GROUP BY templates.id
+                 ORDER BY templates.name",
+                [3],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            counts,
+            vec![("noun".to_string(), 6), ("verb".to_string(), 8)]
+        );
+
+        for sql in [
+            "DELETE FROM substitutes",
+            "BEGIN",
+            "ATTACH 'other.db' AS other",
+        ] {
+            match db.query_rows(sql, [], |row| row.get::<_, i64>(0)) {
+                Err(Error::NotReadOnly(_)) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+
+        // Only the first statement is ever compiled.
+        db.query_rows("SELECT 1; DELETE FROM substitutes", [], |row| {
+            row.get::<_, i64>(0)
+        })
+        .unwrap();
+        assert_eq!(db.get_subs("noun").unwrap().len(), NOUNS.len());
+    }
+
+    #[test]
+    fn static_templates_lookup() {
+        static TEMPLATES: StaticTemplates = StaticTemplates::new(&[
+            ("adj", &["big", "small"]),
+            ("empty", &[]),
+            ("Noun", &["ape", "cat"]),
+        ]);
+
+        let mut rng = Rng::new(7);
+
+        assert_eq!(TEMPLATES.get_templates(), vec!["adj", "empty", "Noun"]);
+        assert_eq!(TEMPLATES.get_subs("noun"), Some(&["ape", "cat"][..]));
+        assert!(TEMPLATES.get_subs("verb").is_none());
+        assert_eq!(TEMPLATES.get_random_sub("empty", &mut rng), Some(""));
+        assert!(["big", "small"].contains(&TEMPLATES.get_random_sub("ADJ", &mut rng).unwrap()));
+    }
+
+    #[test]
+    fn export_as_rust_source() {
+        let mut db = TemplateDatabase::from_path("test22.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("Noun", Some(&["cat", "ape"])).unwrap();
+        db.insert_subs("adj", Some(&["say \"hi\""])).unwrap();
+        db.upsert_subs("Noun", &[SubEntry::new("dog").weight(0)])
+            .unwrap();
+
+        let mut source = Vec::new();
+        db.export_rust_source("WORDS", &mut source).unwrap();
+        let source = String::from_utf8(source).unwrap();
+
+        assert!(
+            source.contains("pub static WORDS: template_substitution_database::StaticTemplates")
+        );
+        let adj = source.find(r#"("adj", &["say \"hi\""]),"#).unwrap();
+        let noun = source.find(r#"("Noun", &["ape", "cat"]),"#).unwrap();
+        assert!(adj < noun);
+    }
+
+    #[test]
+    fn template_source_backends_agree() {
+        fn describe<S: TemplateSource>(
+            source: &S,
+            rng: &mut Rng,
+        ) -> std::result::Result<String, S::Error> {
+            let mut parts = Vec::new();
+            for template in source.templates()? {
+                let subs = source.subs(&template)?.unwrap();
+                let random = source.random_sub(&template, rng)?.unwrap();
+                assert!(random.is_empty() || subs.contains(&random));
+                parts.push(format!("{}={}", template, subs.join(",")));
+            }
+            assert!(source.subs("missing")?.is_none());
+            Ok(parts.join(";"))
+        }
+
+        static TEMPLATES: StaticTemplates =
+            StaticTemplates::new(&[("adj", &["big", "small"]), ("empty", &[])]);
+
+        let mut db = TemplateDatabase::from_path("test23.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("adj", Some(&["small", "big"])).unwrap();
+        db.insert_subs("empty", None).unwrap();
+
+        let mut rng = Rng::new(1);
+
+        assert_eq!(
+            describe(&db, &mut rng).unwrap(),
+            describe(&TEMPLATES, &mut rng).unwrap()
+        );
+    }
+
+    #[test]
+    fn storage_backends_agree() {
+        fn exercise<B: StorageBackend>(backend: &mut B) -> std::result::Result<String, B::Error> {
+            backend.clear()?;
+            backend.insert_subs("noun", &["cat", "dog", "ape"])?;
+            backend.insert_subs("NOUN", &["Cat", "bed"])?;
+            backend.insert_subs("verb", &["run"])?;
+            backend.remove_subs("noun", &["dog", "tree"])?;
+            backend.remove_subs("missing", &["dog"])?;
+            assert!(!backend.remove_template("missing")?);
+            assert!(!backend.rename_template("noun", "verb")?);
+            assert!(backend.rename_template("verb", "action")?);
+            assert!(!backend.rename_sub("noun", "cat", "ape")?);
+            assert!(backend.rename_sub("noun", "cat", "CAT")?);
+
+            let mut state = Vec::new();
+            for template in backend.templates()? {
+                let subs = backend.subs(&template)?.unwrap();
+                state.push(format!("{}={}", template, subs.join(",")));
+            }
+            Ok(state.join(";"))
+        }
+
+        let mut db = TemplateDatabase::from_path("test24.db").unwrap();
+        let mut memory = MemoryBackend::new();
+
+        let expected = "action=run;noun=ape,bed,CAT";
+        assert_eq!(exercise(&mut db).unwrap(), expected);
+        assert_eq!(exercise(&mut memory).unwrap(), expected);
+    }
+
+    #[test]
+    fn cached_backend_invalidates_on_write() {
+        let db = TemplateDatabase::from_path("test25.db").unwrap();
+        let mut cached = CachedBackend::new(db, 1);
+        let mut rng = Rng::new(3);
+
+        cached.clear().unwrap();
+        cached.insert_subs("noun", &["cat"]).unwrap();
+        cached.insert_subs("verb", &["run"]).unwrap();
+
+        assert_eq!(cached.random_sub("noun", &mut rng).unwrap().unwrap(), "cat");
+
+        // Bypassing the decorator is not seen until the entry is invalidated.
+        cached.inner().clear().unwrap();
+        assert_eq!(cached.subs("noun").unwrap().unwrap(), vec!["cat"]);
+        cached.invalidate("noun");
+        assert!(cached.subs("noun").unwrap().is_none());
+
+        cached.insert_subs("noun", &["dog"]).unwrap();
+        assert_eq!(cached.subs("noun").unwrap().unwrap(), vec!["dog"]);
+
+        // Capacity one: looking up another template evicts "noun".
+        assert!(cached.subs("verb").unwrap().is_none());
+        cached.inner().clear().unwrap();
+        assert!(cached.subs("noun").unwrap().is_none());
+    }
+
+    #[test]
+    fn random_picks_per_template() {
+        let mut db = TemplateDatabase::from_path("test26.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("noun", Some(NOUNS)).unwrap();
+        db.insert_subs("adj", Some(&["big", "small"])).unwrap();
+
+        let picks = db.get_random_picks(&[("noun", 2), ("adj", 3)]).unwrap();
+
+        assert_eq!(picks.len(), 2);
+        assert_eq!(picks[0].len(), 2);
+        assert_ne!(picks[0][0], picks[0][1]);
+        assert!(picks[0].iter().all(|x| NOUNS.contains(&x.as_str())));
+
+        let mut adj = picks[1].clone();
+        adj.sort();
+        assert_eq!(adj, vec!["big", "small"]);
+
+        assert!(db.get_random_picks(&[("missing", 1)]).is_err());
+    }
+
+    #[test]
+    fn search_templates_by_prefix_or_glob() {
+        let mut db = TemplateDatabase::from_path("test27.db").unwrap();
+
+        db.clear().unwrap();
+
+        for template in ["quest_start", "Quest_end", "npc_name", "quest%"] {
+            db.insert_subs(template, None).unwrap();
+        }
+
+        assert_eq!(
+            db.search_templates("QUEST_").unwrap(),
+            vec!["Quest_end", "quest_start"]
+        );
+        assert_eq!(db.search_templates("quest%").unwrap(), vec!["quest%"]);
+        assert_eq!(
+            db.search_templates("*_[ns]*").unwrap(),
+            vec!["npc_name", "quest_start"]
+        );
+        assert!(db.search_templates("item_").unwrap().is_empty());
+    }
+
+    #[test]
+    fn substitute_detail() {
+        let mut db = TemplateDatabase::from_path("test28.db").unwrap();
+
+        db.clear().unwrap();
+
+        let id = db.insert_sub_returning_id("Noun", "Cat").unwrap();
+
+        let detail = db.get_sub_detail("noun", "cat").unwrap().unwrap();
+        assert!(detail.created_at.is_some());
+        assert_eq!(
+            detail,
+            SubDetail {
+                id,
+                template: "Noun".to_string(),
+                value: "Cat".to_string(),
+                weight: 1,
+                tags: vec![],
+                metadata: None,
+                use_count: 0,
+                last_used: None,
+                created_at: detail.created_at,
+            }
+        );
+
+        db.set_usage_tracking(true).unwrap();
+        db.get_random_subs("noun").unwrap();
+        let detail = db.get_sub_detail("noun", "cat").unwrap().unwrap();
+        assert_eq!(detail.use_count, 1);
+        assert!(detail.last_used.is_some());
+        assert!(db.get_sub_detail("noun", "dog").unwrap().is_none());
+        assert!(db.get_sub_detail("verb", "cat").unwrap().is_none());
+    }
+
+    #[test]
+    fn replace_substitutes_atomically() {
+        let mut db = TemplateDatabase::from_path("test29.db").unwrap();
+
+        db.clear().unwrap();
+
+        let ids = db
+            .insert_subs_returning_ids("noun", &["cat", "dog", "ape"])
+            .unwrap();
+
+        let diff = db
+            .replace_subs("noun", &["Cat", "ape", "bed", "bed"])
+            .unwrap();
+
+        assert_eq!(
+            diff,
+            SubsDiff {
+                added: vec!["bed".to_string()],
+                removed: vec!["dog".to_string()],
+                updated: vec!["Cat".to_string()],
+            }
+        );
+        assert_eq!(db.get_subs("noun").unwrap(), vec!["ape", "bed", "Cat"]);
+        assert_eq!(
+            db.get_sub_detail("noun", "cat").unwrap().unwrap().id,
+            ids[0]
+        );
+
+        let diff = db.replace_subs("new", &["one"]).unwrap();
+        assert_eq!(diff.added, vec!["one"]);
+    }
+
+    #[test]
+    fn upsert_substitutes_with_attributes() {
+        let mut db = TemplateDatabase::from_path("test30.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("noun", Some(&["cat"])).unwrap();
+
+        let report = db
+            .upsert_subs(
+                "noun",
+                &[
+                    SubEntry::new("CAT").weight(3).tags(&["animal", "pet"]),
+                    SubEntry::new("tree").metadata("{\"kind\":\"plant\"}"),
+                ],
+            )
+            .unwrap();
+        assert_eq!(
+            (report.inserted, report.overwritten, report.skipped),
+            (1, 1, 0)
+        );
+
+        let cat = db.get_sub_detail("noun", "cat").unwrap().unwrap();
+        assert_eq!(cat.value, "cat");
+        assert_eq!(cat.weight, 3);
+        assert_eq!(cat.tags, vec!["animal", "pet"]);
+
+        let tree = db.get_sub_detail("noun", "tree").unwrap().unwrap();
+        assert_eq!(tree.weight, 1);
+        assert_eq!(tree.metadata.as_deref(), Some("{\"kind\":\"plant\"}"));
+
+        let report = db
+            .upsert_subs(
+                "noun",
+                &[
+                    SubEntry::new("cat").weight(3).tags(&["Pet", "animal"]),
+                    SubEntry::new("tree").tags(&["plant"]),
+                ],
+            )
+            .unwrap();
+        assert_eq!(
+            (report.inserted, report.overwritten, report.skipped),
+            (0, 1, 1)
+        );
+
+        let entries = [SubEntry::new("cat").tags(&["PET", "animal", "pet"])];
+        let report = db.upsert_subs("noun", &entries).unwrap();
+        assert_eq!(report.skipped, 1);
+        let template_id =
+            TemplateDatabase::find_template_id_with_transaction(&db.db, "noun").unwrap();
+        let (outcome, _) = TemplateDatabase::execute_insert_entry_with_policy(
+            &db.db,
+            "noun",
+            &template_id,
+            &entries[0],
+            ConflictPolicy::Overwrite,
+        )
+        .unwrap();
+        assert_eq!(outcome, import::InsertOutcome::Skipped);
+
+        db.remove_sub("noun", "cat").unwrap();
+        let stray_tags = db
+            .query_rows("SELECT COUNT(*) FROM substitute_tags", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .unwrap();
+        assert_eq!(stray_tags, vec![1]);
+    }
+
+    #[test]
+    fn upgrade_version_1_database() {
+        {
+            let db = Connection::open("test31.db").unwrap();
+            db.execute_batch(
+                "DROP TABLE IF EXISTS substitute_tags;
+                 DROP TABLE IF EXISTS substitutes;
+                 DROP TABLE IF EXISTS templates;
+                 CREATE TABLE templates (
+                     id INTEGER PRIMARY KEY,
+                     name TEXT NOT NULL UNIQUE COLLATE NOCASE
+                 );
+                 CREATE TABLE substitutes (
+                     id INTEGER PRIMARY KEY,
+                     name TEXT NOT NULL COLLATE NOCASE,
+                     template_id INTEGER NOT NULL REFERENCES templates(id),
+                     UNIQUE(name, template_id)
+                 );
+                 INSERT INTO templates (id, name) VALUES (1, 'noun');
+                 INSERT INTO substitutes (name, template_id) VALUES ('cat', 1);
+                 PRAGMA user_version = 1;",
+            )
+            .unwrap();
+        }
+
+        let mut db = TemplateDatabase::from_path("test31.db").unwrap();
+
+        assert_eq!(
+            TemplateDatabase::get_schema_version(&db.db).unwrap(),
+            DATABASE_VERSION
+        );
+        assert_eq!(db.get_sub_detail("noun", "cat").unwrap().unwrap().weight, 1);
+
+        db.upsert_subs("noun", &[SubEntry::new("cat").tags(&["pet"])])
+            .unwrap();
+        assert_eq!(
+            db.get_sub_detail("noun", "cat").unwrap().unwrap().tags,
+            vec!["pet"]
+        );
+    }
+
+    #[test]
+    fn rename_template_rewrites_patterns() {
+        let mut db = TemplateDatabase::from_path("test32.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("noun", Some(&["cat"])).unwrap();
+        db.insert_subs("verb", Some(&["runs"])).unwrap();
+        db.insert_pattern("sentence", "the {Noun} {verb}, {noun}!")
+            .unwrap();
+        db.insert_pattern("action", "{verb} {").unwrap();
+
+        let report = db.rename_template_propagating("noun", "animal").unwrap();
+
+        assert_eq!(
+            report,
+            RenameReport {
+                renamed: true,
+                patterns: vec!["sentence".to_string()],
+            }
+        );
+        assert_eq!(
+            db.get_pattern("sentence").unwrap().unwrap(),
+            "the {animal} {verb}, {animal}!"
+        );
+        assert_eq!(db.get_pattern("action").unwrap().unwrap(), "{verb} {");
+        assert_eq!(db.render_pattern("sentence").unwrap(), "the cat runs, cat!");
+        assert_eq!(db.get_patterns().unwrap(), vec!["action", "sentence"]);
+
+        let report = db.rename_template_propagating("missing", "other").unwrap();
+        assert!(!report.renamed && report.patterns.is_empty());
+    }
+
+    #[test]
+    fn locked_template_refuses_changes() {
+        let mut db = TemplateDatabase::from_path("test33.db").unwrap();
+
+        db.unlock_template("noun").unwrap();
+        db.clear().unwrap();
+
+        db.insert_subs("noun", Some(&["cat", "dog"])).unwrap();
+        db.insert_subs("verb", Some(&["run"])).unwrap();
+
+        assert!(db.lock_template("Noun").unwrap());
+        assert!(!db.lock_template("missing").unwrap());
+        assert!(db.is_template_locked("noun").unwrap());
+
+        let is_locked =
+            |result: Result<_>| matches!(result, Err(Error::TemplateLocked(x)) if x == "noun");
+        assert!(is_locked(db.insert_sub("noun", "ape").map(|_| ())));
+        assert!(is_locked(db.remove_sub("noun", "cat").map(|_| ())));
+        assert!(is_locked(db.rename_template("noun", "thing").map(|_| ())));
+        assert!(is_locked(db.replace_subs("noun", &["ape"]).map(|_| ())));
+        assert!(is_locked(db.remove_template("noun").map(|_| ())));
+        assert!(is_locked(
+            db.template("noun").unwrap().add("ape").map(|_| ())
+        ));
+        assert!(is_locked(db.clear()));
+        assert_eq!(db.get_subs("noun").unwrap(), vec!["cat", "dog"]);
+
+        db.insert_sub("verb", "jump").unwrap();
+
+        assert!(db.unlock_template("noun").unwrap());
+        assert!(db.insert_sub("noun", "ape").unwrap());
+        db.clear().unwrap();
+    }
+
+    #[test]
+    fn disabled_substitutes_are_never_selected() {
+        let mut db = TemplateDatabase::from_path("test34.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.upsert_subs(
+            "noun",
+            &[
+                SubEntry::new("cat").weight(3),
+                SubEntry::new("dog").weight(0),
+                SubEntry::new("ape").weight(-1),
+            ],
+        )
+        .unwrap();
+        db.upsert_subs("off", &[SubEntry::new("none").weight(0)])
+            .unwrap();
+
+        assert_eq!(db.get_subs("noun").unwrap(), vec!["ape", "cat", "dog"]);
+        assert_eq!(db.get_disabled_subs("noun").unwrap(), vec!["ape", "dog"]);
+        assert_eq!(
+            db.query().template("noun").enabled(true).fetch().unwrap(),
+            vec!["cat"]
+        );
+
+        let mut rng = Rng::new(5);
+        for _ in 0..20 {
+            assert_eq!(db.get_random_subs("noun").unwrap(), "cat");
+            assert_eq!(db.template("noun").unwrap().random().unwrap(), "cat");
+            assert_eq!(db.render("{noun}").unwrap(), "cat");
+            assert_eq!(db.random_sub("noun", &mut rng).unwrap().unwrap(), "cat");
+        }
+        assert_eq!(
+            db.get_random_picks(&[("noun", 3)]).unwrap(),
+            vec![vec!["cat"]]
+        );
+        assert_eq!(db.get_random_subs("off").unwrap(), "");
+
+        let mut cache = TemplateCache::load(&db).unwrap();
+        assert_eq!(cache.get_subs("noun").unwrap().len(), 3);
+        assert_eq!(cache.get_random_sub("noun"), Some("cat"));
+        assert_eq!(cache.get_random_sub("off"), Some(""));
+    }
+
+    #[test]
+    fn canonical_substitutes_collapse_variants() {
+        let mut db = TemplateDatabase::from_path("test35.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs(
+            "noun",
+            Some(&[
+                "Émile",
+                "cat",
+                "émile",
+                " Cat ",
+                "new  york",
+                "New York",
+                "dog",
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            db.get_subs_canonical("noun").unwrap(),
+            vec![
+                CanonicalSub {
+                    value: "cat".to_string(),
+                    collapsed: vec![" Cat ".to_string()],
+                },
+                CanonicalSub {
+                    value: "dog".to_string(),
+                    collapsed: vec![],
+                },
+                CanonicalSub {
+                    value: "new  york".to_string(),
+                    collapsed: vec!["New York".to_string()],
+                },
+                CanonicalSub {
+                    value: "Émile".to_string(),
+                    collapsed: vec!["émile".to_string()],
+                },
+            ]
+        );
+        assert_eq!(db.get_subs("noun").unwrap().len(), 7);
+    }
+
+    #[test]
+    fn install_and_uninstall_pack() {
+        let mut db = TemplateDatabase::from_path("test36.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("noun", Some(&["cat"])).unwrap();
+
+        let pack = Pack::new("space", "1.2.0")
+            .template(
+                "noun",
+                &[
+                    SubEntry::new("cat"),
+                    SubEntry::new("rocket").tags(&["space"]),
+                ],
+            )
+            .template("planet", &[SubEntry::new("Mars"), SubEntry::new("Venus")])
+            .pattern("launch", "the {noun} flies to {planet}");
+
+        let report = db.install_pack(&pack, ConflictPolicy::Skip).unwrap();
+        assert_eq!(
+            (report.templates_created, report.inserted, report.skipped),
+            (1, 3, 1)
+        );
+        assert_eq!(db.get_packs().unwrap(), vec!["space"]);
+        assert_eq!(
+            db.get_pack_version("SPACE").unwrap().as_deref(),
+            Some("1.2.0")
+        );
+        assert_eq!(db.get_subs("noun").unwrap(), vec!["cat", "rocket"]);
+        assert!(matches!(
+            db.install_pack(&pack, ConflictPolicy::Skip),
+            Err(Error::PackInstalled(_))
+        ));
+
+        let clash = Pack::new("other", "1.0.0")
+            .template("verb", &[SubEntry::new("run")])
+            .pattern("launch", "{verb}");
+        assert!(matches!(
+            db.install_pack(&clash, ConflictPolicy::Skip),
+            Err(Error::PatternExists(_))
+        ));
+        assert!(db.find_template("verb").unwrap().is_none());
+
+        assert!(db.uninstall_pack("space").unwrap());
+        assert!(!db.uninstall_pack("space").unwrap());
+        assert_eq!(db.get_subs("noun").unwrap(), vec!["cat"]);
+        assert_eq!(db.get_templates().unwrap(), vec!["noun"]);
+        assert!(db.get_pattern("launch").unwrap().is_none());
+        assert!(db.get_packs().unwrap().is_empty());
+
+        db.install_pack(&pack, ConflictPolicy::Skip).unwrap();
+        db.insert_sub("planet", "Pluto").unwrap();
+        db.remove_sub("noun", "rocket").unwrap();
+        db.insert_sub("noun", "rocket").unwrap();
+        db.uninstall_pack("space").unwrap();
+        assert_eq!(db.get_subs("planet").unwrap(), vec!["Pluto"]);
+        assert_eq!(db.get_subs("noun").unwrap(), vec!["cat", "rocket"]);
+    }
+
+    #[test]
+    fn pack_dependencies_are_checked() {
+        let mut db = TemplateDatabase::from_path("test37.db").unwrap();
+
+        db.clear().unwrap();
+
+        let stories = Pack::new("stories", "0.1.0")
+            .depends_on("base", "^1.2")
+            .pattern("story", "a {noun}");
+
+        assert!(matches!(
+            db.install_pack(&stories, ConflictPolicy::Skip),
+            Err(Error::MissingDependency { dependency, .. }) if dependency == "base"
+        ));
+
+        let old_base = Pack::new("base", "1.1.9").template("noun", &[SubEntry::new("cat")]);
+        db.install_pack(&old_base, ConflictPolicy::Skip).unwrap();
+        assert!(matches!(
+            db.install_pack(&stories, ConflictPolicy::Skip),
+            Err(Error::IncompatibleDependency { installed, .. }) if installed == "1.1.9"
+        ));
+        assert!(db.get_pattern("story").unwrap().is_none());
+        db.uninstall_pack("base").unwrap();
+
+        db.install_pack(
+            &Pack::new("base", "1.4").template("noun", &[SubEntry::new("cat")]),
+            ConflictPolicy::Skip,
+        )
+        .unwrap();
+        db.install_pack(&stories, ConflictPolicy::Skip).unwrap();
+        assert_eq!(db.render_pattern("story").unwrap(), "a cat");
+
+        let strict = Pack::new("strict", "1.0.0").depends_on("base", ">=1.0, <1.4");
+        assert!(matches!(
+            db.install_pack(&strict, ConflictPolicy::Skip),
+            Err(Error::IncompatibleDependency { .. })
+        ));
+        let broken = Pack::new("broken", "1.0.0").depends_on("base", ">=one");
+        assert!(matches!(
+            db.install_pack(&broken, ConflictPolicy::Skip),
+            Err(Error::InvalidVersion(_))
+        ));
+        assert!(matches!(
+            db.install_pack(&Pack::new("bad", "v2"), ConflictPolicy::Skip),
+            Err(Error::InvalidVersion(_))
+        ));
+
+        assert!(matches!(
+            db.uninstall_pack("base"),
+            Err(Error::PackRequired { dependent, .. }) if dependent == "stories"
+        ));
+        db.uninstall_pack("stories").unwrap();
+        db.uninstall_pack("base").unwrap();
+        assert!(db.get_packs().unwrap().is_empty());
+    }
+
+    #[test]
+    fn substitute_length_distribution() {
+        let mut db = TemplateDatabase::from_path("test38.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs(
+            "noun",
+            Some(&["cat", "dog", "tree", "the cat sat on the mat", "ape"]),
+        )
+        .unwrap();
+        db.insert_subs("empty", Some(&[])).unwrap();
+
+        assert_eq!(
+            db.sub_length_histogram("noun").unwrap(),
+            vec![(3, 3), (4, 1), (22, 1)]
+        );
+        assert_eq!(
+            db.sub_word_count_histogram("noun").unwrap(),
+            vec![(1, 4), (6, 1)]
+        );
+
+        let stats = db.sub_length_stats("noun").unwrap();
+        assert_eq!((stats.count, stats.min, stats.max), (5, 3, 22));
+        assert!((stats.mean - 7.0).abs() < 1e-9);
+
+        assert_eq!(
+            db.sub_length_stats("empty").unwrap(),
+            LengthStats::default()
+        );
+        assert!(db.sub_length_histogram("missing").is_err());
+    }
+
+    #[test]
+    fn prune_never_used_substitutes() {
+        let mut db = TemplateDatabase::from_path("test39.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("noun", Some(&["cat", "dog", "ape"]))
+            .unwrap();
+        db.upsert_subs("noun", &[SubEntry::new("dog").weight(0)])
+            .unwrap();
+        db.db
+            .execute("UPDATE substitutes SET created_at = created_at - 7200", [])
+            .unwrap();
+        db.insert_sub("noun", "bed").unwrap();
+
+        db.set_usage_tracking(true).unwrap();
+        db.db
+            .execute(
+                "UPDATE usage_tracking SET started_at = started_at - 7200",
+                [],
+            )
+            .unwrap();
+        db.template("noun").unwrap().random().unwrap();
+        db.get_random_subs("noun").unwrap();
+
+        let used: u64 = ["ape", "bed", "cat"]
+            .iter()
+            .map(|x| db.get_sub_usage("noun", x).unwrap().unwrap().use_count)
+            .sum();
+        assert_eq!(used, 2);
+        assert!(db
+            .get_sub_usage("noun", "bed")
+            .unwrap()
+            .unwrap()
+            .created_at
+            .is_some());
+
+        let hour = Duration::from_secs(3600);
+        let preview = db.prune_unused_dry_run("noun", hour).unwrap();
+        assert!(preview.pruned.contains(&"dog".to_string()));
+        assert!(!preview.pruned.contains(&"bed".to_string()));
+        assert_eq!(preview.remaining + preview.pruned.len(), 4);
+        assert_eq!(db.get_subs("noun").unwrap().len(), 4);
+
+        let report = db.prune_unused("noun", hour).unwrap();
+        assert_eq!(report, preview);
+        assert_eq!(db.get_subs("noun").unwrap().len(), report.remaining);
+        assert!(db.get_subs("noun").unwrap().contains(&"bed".to_string()));
+
+        db.set_usage_tracking(false).unwrap();
+        db.get_random_subs("noun").unwrap();
+        let used: u64 = db
+            .get_subs("noun")
+            .unwrap()
+            .iter()
+            .map(|x| db.get_sub_usage("noun", x).unwrap().unwrap().use_count)
+            .sum();
+        assert_eq!(used, 2);
+        assert!(matches!(
+            db.prune_unused("noun", hour),
+            Err(Error::UsageNotTracked)
+        ));
+    }
+
+    #[test]
+    fn usage_tracking_persists_and_covers_renders() {
+        let mut db = TemplateDatabase::from_path("test67.db").unwrap();
+
+        db.clear().unwrap();
+        db.set_usage_tracking(false).unwrap();
+        db.insert_subs("noun", Some(&["cat"])).unwrap();
+        db.insert_subs("verb", Some(&["run"])).unwrap();
+        db.insert_subs("adj", Some(&["red"])).unwrap();
+        db.db
+            .execute("UPDATE substitutes SET created_at = created_at - 7200", [])
+            .unwrap();
+        db.set_usage_tracking(true).unwrap();
+        db.db
+            .execute(
+                "UPDATE usage_tracking SET started_at = started_at - 7200",
+                [],
+            )
+            .unwrap();
+        drop(db);
+
+        let db = TemplateDatabase::from_path("test67.db").unwrap();
+        assert_eq!(
+            db.render("{noun} and {noun@1}{noun@1}").unwrap(),
+            "cat and catcat"
+        );
+        db.generate_corpus("{verb}", 3, std::io::sink()).unwrap();
+        assert_eq!(db.generate_unique("{verb}", 1).unwrap(), vec!["run"]);
+        let count = |db: &TemplateDatabase, template, sub| {
+            db.get_sub_usage(template, sub).unwrap().unwrap().use_count
+        };
+        assert_eq!(count(&db, "noun", "cat"), 2);
+        assert_eq!(count(&db, "verb", "run"), 4);
+
+        let mut cache = TemplateCache::load(&db).unwrap();
+        assert_eq!(cache.get_random_sub("adj"), Some("red"));
+        assert_eq!(count(&db, "adj", "red"), 0);
+        cache.record_usage(&db).unwrap();
+        assert_eq!(count(&db, "adj", "red"), 1);
+        assert!(!cache.is_stale(&db).unwrap());
+
+        let cached = CachedBackend::new(db, 4);
+        let mut rng = Rng::new(1);
+        cached.random_sub("adj", &mut rng).unwrap();
+        cached.random_sub("adj", &mut rng).unwrap();
+        let mut db = cached.into_inner();
+        assert_eq!(count(&db, "adj", "red"), 3);
+
+        db.insert_sub("noun", "dog").unwrap();
+        db.db
+            .execute("UPDATE substitutes SET created_at = created_at - 7200", [])
+            .unwrap();
+        let report = db.prune_unused("noun", Duration::from_secs(3600)).unwrap();
+        assert_eq!(report.pruned, vec!["dog"]);
+
+        db.set_usage_tracking(false).unwrap();
+        drop(db);
+
+        let mut db = TemplateDatabase::from_path("test67.db").unwrap();
+        db.render("{noun}").unwrap();
+        assert_eq!(count(&db, "noun", "cat"), 2);
+        assert!(matches!(
+            db.prune_unused("noun", Duration::from_secs(3600)),
+            Err(Error::UsageNotTracked)
+        ));
+    }
+
+    #[test]
+    fn prune_requires_usage_tracking() {
+        let mut db = TemplateDatabase::from_path("test64.db").unwrap();
+
+        db.clear().unwrap();
+        db.db.execute("DELETE FROM usage_tracking", []).unwrap();
+        db.insert_subs("noun", Some(&["cat", "dog"])).unwrap();
+        db.db
+            .execute("UPDATE substitutes SET created_at = created_at - 7200", [])
+            .unwrap();
+
+        let hour = Duration::from_secs(3600);
+        assert!(matches!(
+            db.prune_unused("noun", hour),
+            Err(Error::UsageNotTracked)
+        ));
+        assert!(matches!(
+            db.prune_unused_dry_run("noun", hour),
+            Err(Error::UsageNotTracked)
+        ));
+        assert_eq!(db.get_subs("noun").unwrap().len(), 2);
+
+        db.set_usage_tracking(true).unwrap();
+        let report = db.prune_unused("noun", hour).unwrap();
+        assert!(report.pruned.is_empty());
+        assert_eq!(report.remaining, 2);
+    }
+
+    #[test]
+    fn timeout_interrupts_long_queries() {
+        let mut db = TemplateDatabase::from_path("test40.db").unwrap();
+
+        db.clear().unwrap();
+        db.insert_subs("noun", Some(&["cat"])).unwrap();
+
+        let timeout = Duration::from_millis(50);
+        let result = db.with_timeout(timeout, |db| {
+            db.query_rows(
+                "WITH RECURSIVE counter(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM counter)
+                 SELECT COUNT(*) FROM counter",
+                [],
+                |row| row.get::<_, i64>(0),
+            )
+        });
+        assert!(matches!(result, Err(Error::Timeout(x)) if x == timeout));
+
+        let subs = db
+            .with_timeout(timeout, |db| Ok(db.get_subs("noun")?))
+            .unwrap();
+        assert_eq!(subs, vec!["cat"]);
+
+        std::thread::sleep(timeout);
+        assert_eq!(db.get_subs("noun").unwrap(), vec!["cat"]);
+    }
+
+    #[test]
+    fn snapshot_reads_are_consistent() {
+        let mut db = TemplateDatabase::from_path("test41.db").unwrap();
+        db.db
+            .query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))
+            .unwrap();
+
+        db.clear().unwrap();
+        db.insert_subs("noun", Some(&["cat"])).unwrap();
+
+        let mut writer = TemplateDatabase::from_path("test41.db").unwrap();
+
+        let (before, after) = db
+            .read_snapshot(|view| {
+                let before = view.get_subs("noun")?;
+                writer.insert_sub("noun", "dog")?;
+                writer.insert_sub("verb", "run")?;
+                let after = view.get_subs("noun")?;
+                assert_eq!(view.get_templates()?, vec!["noun"]);
+                assert_eq!(view.get_random_picks(&[("noun", 2)])?, vec![vec!["cat"]]);
+                assert!(view.clear().is_err());
+                Ok((before, after))
+            })
+            .unwrap();
+
+        assert_eq!(before, after);
+        assert_eq!(db.get_subs("noun").unwrap(), vec!["cat", "dog"]);
+        db.insert_sub("noun", "ape").unwrap();
+
+        db.begin_batch().unwrap();
+        db.insert_sub("noun", "bee").unwrap();
+        let subs = db
+            .read_snapshot(|view| {
+                assert!(view.clear().is_err());
+                Ok(view.get_subs("noun")?)
+            })
+            .unwrap();
+        assert_eq!(subs, vec!["ape", "bee", "cat", "dog"]);
+        assert!(db.is_batching());
+        db.insert_sub("noun", "owl").unwrap();
+        db.end_batch().unwrap();
+        assert_eq!(writer.get_subs("noun").unwrap().len(), 5);
+
+        db.set_usage_tracking(true).unwrap();
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            db.read_snapshot(|_| -> Result<()> { panic!("reader failed") })
+        }));
+        assert!(panicked.is_err());
+        assert!(db.track_usage);
+        assert!(db.db.is_autocommit());
+        db.insert_sub("noun", "yak").unwrap();
+    }
+
+    #[test]
+    fn export_and_import_wordlists() {
+        let mut db = TemplateDatabase::from_path("test42.db").unwrap();
+        let dir = std::path::Path::new("test42_wordlists");
+        let _ = std::fs::remove_dir_all(dir);
+
+        db.clear().unwrap();
+        db.insert_subs("noun", Some(&["dog", "cat"])).unwrap();
+        db.insert_subs("Noun/Plural", Some(&["cats"])).unwrap();
+        db.insert_subs("verb", Some(&["run"])).unwrap();
+
+        db.export_wordlists(dir).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(dir.join("noun.txt")).unwrap(),
+            "cat\ndog\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("manifest.tsv")).unwrap(),
+            "noun.txt\tnoun\nnoun_plural.txt\tNoun/Plural\nverb.txt\tverb\n"
+        );
+
+        db.remove_template("verb").unwrap();
+        db.export_wordlists(dir).unwrap();
+        assert!(!dir.join("verb.txt").exists());
+
+        db.clear().unwrap();
+        let report = db.import_wordlists(dir, ConflictPolicy::Skip).unwrap();
+        assert_eq!((report.templates_created, report.inserted), (2, 3));
+        assert_eq!(db.get_templates().unwrap(), vec!["noun", "Noun/Plural"]);
+        assert_eq!(db.get_subs("noun").unwrap(), vec!["cat", "dog"]);
+
+        for file_name in ["../noun.txt", "/etc/hostname", "sub/noun.txt", ".."] {
+            std::fs::write(dir.join("manifest.tsv"), format!("{}\tevil\n", file_name)).unwrap();
+            match db.import_wordlists(dir, ConflictPolicy::Skip) {
+                Err(Error::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::InvalidData),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        assert!(db.find_template("evil").unwrap().is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn full_export_round_trips_every_attribute() {
+        let mut db = TemplateDatabase::from_path("test43.db").unwrap();
+
+        db.unlock_template("noun").unwrap();
+        db.clear().unwrap();
+
+        db.insert_subs("noun", Some(&["cat", "two\tlines\nhere\\"]))
+            .unwrap();
+        db.upsert_subs(
+            "noun",
+            &[SubEntry::new("dog")
+                .weight(0)
+                .tags(&["pet", "animal"])
+                .metadata("{\"legs\":4}")],
+        )
+        .unwrap();
+        db.db
+            .execute(
+                "UPDATE substitutes SET use_count = 3, last_used = 1700000000, created_at = NULL
+                 WHERE name = 'cat'",
+                [],
+            )
+            .unwrap();
+        db.install_pack(
+            &Pack::new("base", "1.0.0")
+                .template("verb", &[SubEntry::new("run")])
+                .pattern("sentence", "{noun} {verb}"),
+            ConflictPolicy::Skip,
+        )
+        .unwrap();
+        db.lock_template("noun").unwrap();
+        db.set_usage_tracking(true).unwrap();
+
+        let mut dump = Vec::new();
+        db.export_full(&mut dump).unwrap();
+
+        let mut copy = TemplateDatabase::from_path("test44.db").unwrap();
+        copy.unlock_template("noun").unwrap();
+        copy.clear().unwrap();
+        copy.set_usage_tracking(false).unwrap();
+        copy.import_full(dump.as_slice(), ConflictPolicy::Error)
+            .unwrap();
+
+        let mut copied_dump = Vec::new();
+        copy.export_full(&mut copied_dump).unwrap();
+        assert_eq!(
+            String::from_utf8(copied_dump).unwrap(),
+            String::from_utf8(dump.clone()).unwrap()
+        );
+
+        assert!(copy.is_template_locked("noun").unwrap());
+        assert_eq!(
+            copy.get_sub_detail("noun", "dog").unwrap(),
+            db.get_sub_detail("noun", "dog").unwrap()
+        );
+        assert_eq!(
+            copy.get_sub_usage("noun", "cat")
+                .unwrap()
+                .unwrap()
+                .created_at,
+            None
+        );
+        assert_eq!(copy.get_packs().unwrap(), vec!["base"]);
+        let report = copy
+            .prune_unused_dry_run("noun", Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!((report.pruned.len(), report.remaining), (0, 3));
+        copy.render("{verb}").unwrap();
+        assert_eq!(
+            copy.get_sub_usage("verb", "run")
+                .unwrap()
+                .unwrap()
+                .use_count,
+            1
+        );
+
+        assert!(matches!(
+            copy.import_full(dump.as_slice(), ConflictPolicy::Skip),
+            Err(Error::TemplateLocked(_))
+        ));
+
+        copy.unlock_template("noun").unwrap();
+        copy.clear().unwrap();
+        let broken = "template-substitution-database full 1\ntemplate\t1\tnoun\n";
+        assert!(matches!(
+            copy.import_full(broken.as_bytes(), ConflictPolicy::Error),
+            Err(Error::InvalidFormat { line: 2, .. })
+        ));
+        assert!(copy.get_templates().unwrap().is_empty());
+
+        db.unlock_template("noun").unwrap();
+    }
+
+    #[test]
+    fn full_import_merges_into_existing_rows() {
+        let mut db = TemplateDatabase::from_path("test68.db").unwrap();
+
+        db.clear().unwrap();
+        db.set_usage_tracking(true).unwrap();
+        db.upsert_subs(
+            "noun",
+            &[
+                SubEntry::new("cat").tags(&["pet"]),
+                SubEntry::new("dog").weight(2),
+            ],
+        )
+        .unwrap();
+        db.insert_pattern("sentence", "{noun}").unwrap();
+        db.install_pack(
+            &Pack::new("zoo", "1.0.0").template("bird", &[SubEntry::new("owl")]),
+            ConflictPolicy::Skip,
+        )
+        .unwrap();
+        let mut dump = Vec::new();
+        db.export_full(&mut dump).unwrap();
+
+        let mut copy = TemplateDatabase::from_path("test69.db").unwrap();
+        copy.clear().unwrap();
+        copy.set_usage_tracking(false).unwrap();
+        copy.insert_subs("verb", Some(&["run"])).unwrap();
+        copy.upsert_subs("noun", &[SubEntry::new("CAT").weight(5)])
+            .unwrap();
+        copy.insert_pattern("sentence", "{verb}").unwrap();
+
+        assert!(matches!(
+            copy.import_full(dump.as_slice(), ConflictPolicy::Error),
+            Err(Error::Conflict { .. })
+        ));
+        assert!(copy.get_sub_detail("noun", "dog").unwrap().is_none());
+
+        copy.import_full(dump.as_slice(), ConflictPolicy::Skip)
+            .unwrap();
+        let cat = copy.get_sub_detail("noun", "cat").unwrap().unwrap();
+        assert_eq!((cat.value.as_str(), cat.weight), ("CAT", 5));
+        assert!(cat.tags.is_empty());
+        assert_eq!(
+            copy.get_sub_detail("noun", "dog").unwrap().unwrap().weight,
+            2
+        );
+        assert_eq!(copy.get_subs("verb").unwrap(), vec!["run"]);
+        assert_eq!(copy.get_pattern("sentence").unwrap().unwrap(), "{verb}");
+        assert_eq!(copy.get_subs("bird").unwrap(), vec!["owl"]);
+        assert!(!copy.track_usage);
+
+        assert!(matches!(
+            copy.import_full(dump.as_slice(), ConflictPolicy::Skip),
+            Err(Error::PackInstalled(_))
+        ));
+        copy.uninstall_pack("zoo").unwrap();
+        assert!(copy.find_template("bird").unwrap().is_none());
+        assert_eq!(copy.get_subs("noun").unwrap().len(), 2);
+
+        copy.import_full(dump.as_slice(), ConflictPolicy::Overwrite)
+            .unwrap();
+        let cat = copy.get_sub_detail("noun", "cat").unwrap().unwrap();
+        assert_eq!((cat.value.as_str(), cat.weight), ("cat", 1));
+        assert_eq!(cat.tags, vec!["pet"]);
+        assert_eq!(copy.get_pattern("sentence").unwrap().unwrap(), "{noun}");
+        assert_eq!(copy.get_subs("noun").unwrap(), vec!["cat", "dog"]);
+    }
+
+    #[test]
+    fn remove_template_checks_pattern_references() {
+        let mut db = TemplateDatabase::from_path("test45.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("noun", Some(&["cat"])).unwrap();
+        db.insert_subs("verb", Some(&["runs"])).unwrap();
+        db.insert_pattern("sentence", "the {NOUN} {verb}").unwrap();
+        db.insert_pattern("shout", "{noun}!").unwrap();
+        db.insert_pattern("action", "{verb}").unwrap();
+
+        match db.remove_template_checked("noun", false) {
+            Err(Error::TemplateReferenced { template, patterns }) => {
+                assert_eq!(template, "noun");
+                assert_eq!(patterns, vec!["sentence", "shout"]);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(db.get_subs("noun").unwrap(), vec!["cat"]);
+
+        let report = db.remove_template_checked("noun", true).unwrap();
+        assert_eq!(
+            report,
+            RemoveReport {
+                removed: true,
+                patterns: vec!["sentence".to_string(), "shout".to_string()],
+            }
+        );
+        assert_eq!(db.get_patterns().unwrap(), vec!["action"]);
+        assert_eq!(db.get_templates().unwrap(), vec!["verb"]);
+
+        let report = db.remove_template_checked("missing", false).unwrap();
+        assert!(!report.removed);
+    }
+
+    #[test]
+    fn seeded_render_isolates_template_streams() {
+        let mut db = TemplateDatabase::from_path("test46.db").unwrap();
+
+        db.clear().unwrap();
+
+        db.insert_subs("noun", Some(&["cat", "dog", "tree", "cup", "ape", "bed"]))
+            .unwrap();
+        db.insert_subs("adj", Some(ADJECTIVES)).unwrap();
+
+        let seed = Rng::new(42).derive("session-1");
+        let nouns = db.render_seeded("{noun} {noun} {noun}", &seed).unwrap();
+        assert_eq!(
+            db.render_seeded("{noun} {noun} {noun}", &seed).unwrap(),
+            nouns
+        );
+
+        let mixed = db
+            .render_seeded("{noun} {adj} {noun} {ADJ} {noun}", &seed)
+            .unwrap();
+        let mixed_nouns: Vec<&str> = mixed.split(' ').step_by(2).collect();
+        assert_eq!(mixed_nouns.join(" "), nouns);
+
+        assert_eq!(
+            Rng::new(7).derive("Noun").next_u64(),
+            Rng::new(7).derive("noun").next_u64()
+        );
+    }
+
+    #[test]
+    fn insert_filter_skips_known_duplicates() {
+        let mut db = TemplateDatabase::from_path("test47.db").unwrap();
+        let mut other = TemplateDatabase::from_path("test47.db").unwrap();
+
+        db.unlock_template("noun").unwrap();
+        db.clear().unwrap();
+        db.insert_subs("noun", Some(&["cat"])).unwrap();
+
+        let mut filter = InsertFilter::new();
+
+        assert!(!filter.insert_sub(&mut db, "noun", "CAT").unwrap());
+        assert!(filter.insert_sub(&mut db, "noun", "dog").unwrap());
+        assert!(!filter.insert_sub(&mut db, "Noun", "dog").unwrap());
+        assert_eq!(
+            filter
+                .insert_subs(&mut db, "noun", &["dog", "ape", "cat"])
+                .unwrap(),
+            vec!["ape"]
+        );
+        assert!(filter.insert_sub(&mut db, "verb", "run").unwrap());
+
+        other.remove_sub("noun", "dog").unwrap();
+        assert!(filter.insert_sub(&mut db, "noun", "dog").unwrap());
+
+        db.remove_sub("noun", "cat").unwrap();
+        assert!(filter.insert_sub(&mut db, "noun", "cat").unwrap());
+
+        other.lock_template("noun").unwrap();
+        assert!(matches!(
+            filter.insert_sub(&mut db, "noun", "cat"),
+            Err(Error::TemplateLocked(_))
+        ));
+        other.unlock_template("noun").unwrap();
+
+        assert_eq!(db.get_subs("noun").unwrap(), vec!["ape", "cat", "dog"]);
+    }
+
+    #[test]
+    fn plan_and_merge_templates() {
+        let mut db = TemplateDatabase::from_path("test48.db").unwrap();
+
+        db.clear().unwrap();
+        db.insert_subs("animal", Some(&["cat", "Dog", "ape"]))
+            .unwrap();
+        db.insert_subs("creature", Some(&["dog", "bat"])).unwrap();
+        db.insert_pattern("greeting", "hello {Animal} and {creature}")
+            .unwrap();
+
+        let plan = db
+            .plan_merge_templates("animal", "creature", ConflictPolicy::Overwrite)
+            .unwrap();
+        assert_eq!(plan.moved, vec!["ape", "cat"]);
+        assert_eq!(
+            plan.collisions,
+            vec![MergeCollision {
+                source: "Dog".to_string(),
+                target: "dog".to_string(),
+            }]
+        );
+        assert_eq!(plan.patterns, vec!["greeting"]);
+        assert_eq!(
+            (plan.source_count, plan.target_count, plan.merged_count),
+            (3, 2, 4)
+        );
+        assert_eq!(db.get_subs("animal").unwrap(), vec!["ape", "cat", "Dog"]);
+
+        assert!(matches!(
+            db.merge_templates("animal", "creature", ConflictPolicy::Error),
+            Err(Error::Conflict { .. })
+        ));
+        assert_eq!(db.get_subs("creature").unwrap(), vec!["bat", "dog"]);
+
+        let merged = db
+            .merge_templates("animal", "creature", ConflictPolicy::Overwrite)
+            .unwrap();
+        assert_eq!(merged, plan);
+        assert!(!db.get_templates().unwrap().contains(&"animal".to_string()));
+        assert_eq!(
+            db.get_subs("creature").unwrap(),
+            vec!["ape", "bat", "cat", "Dog"]
+        );
+        assert_eq!(
+            db.get_pattern("greeting").unwrap().unwrap(),
+            "hello {creature} and {creature}"
+        );
+    }
+
+    #[test]
+    fn render_with_bindings() {
+        let mut db = TemplateDatabase::from_path("test49.db").unwrap();
+
+        db.clear().unwrap();
+        db.insert_subs("noun", Some(&["cat"])).unwrap();
+
+        let vars = HashMap::from([("player", "Ada"), ("noun", "dog")]);
+        assert_eq!(
+            db.render_with("{Player} pets the {noun}", &vars).unwrap(),
+            "Ada pets the dog"
+        );
+
+        let vars = HashMap::from([("player", "Ada")]);
+        assert_eq!(
+            db.render_with("{player} pets the {noun}", &vars).unwrap(),
+            "Ada pets the cat"
+        );
+        assert!(db.render_with("{player} pets the {bird}", &vars).is_err());
+    }
+
+    #[test]
+    fn sticky_placeholders_repeat_their_choice() {
+        let mut db = TemplateDatabase::from_path("test50.db").unwrap();
+
+        db.clear().unwrap();
+        db.insert_subs("noun", Some(NOUNS)).unwrap();
+
+        for _ in 0..20 {
+            let line = db.render("{noun@1}|{noun@2}|{Noun@1}").unwrap();
+            let words: Vec<&str> = line.split('|').collect();
+            assert_eq!(words[0], words[2]);
+        }
+
+        let unique = db
+            .generate_unique("{noun@a}={noun@a}", NOUNS.len())
+            .unwrap();
+        assert!(unique.iter().all(|x| {
+            let (left, right) = x.split_once('=').unwrap();
+            left == right
+        }));
+
+        db.insert_pattern("story", "the {noun@1} saw the {noun@1}")
+            .unwrap();
+        let report = db.rename_template_propagating("noun", "thing").unwrap();
+        assert_eq!(report.patterns, vec!["story"]);
+        assert_eq!(
+            db.get_pattern("story").unwrap().unwrap(),
+            "the {thing@1} saw the {thing@1}"
+        );
+    }
+
+    #[test]
+    fn template_names_containing_at_are_not_split() {
+        let mut db = TemplateDatabase::from_path("test65.db").unwrap();
+
+        db.clear().unwrap();
+        db.insert_subs("user@host", Some(&["alice"])).unwrap();
+        db.insert_subs("user", Some(&["bob"])).unwrap();
+
+        assert!(db.render("{user@host} {User@Host} {user@1}").unwrap() == "alice alice bob");
+
+        db.insert_pattern("login", "{user@host}/{user@x}").unwrap();
+        let report = db.rename_template_propagating("user", "person").unwrap();
+        assert_eq!(report.patterns, vec!["login"]);
+        assert_eq!(
+            db.get_pattern("login").unwrap().unwrap(),
+            "{user@host}/{person@x}"
+        );
+
+        let report = db
+            .rename_template_propagating("user@host", "account")
+            .unwrap();
+        assert_eq!(report.patterns, vec!["login"]);
+        assert_eq!(
+            db.get_pattern("login").unwrap().unwrap(),
+            "{account}/{person@x}"
+        );
+        assert!(db.render_pattern("login").unwrap() == "alice/bob");
+    }
+
+    #[test]
+    fn optional_segments_in_patterns() {
+        let mut db = TemplateDatabase::from_path("test51.db").unwrap();
+
+        db.clear().unwrap();
+        db.insert_subs("noun", Some(&["cat", "dog"])).unwrap();
+        db.insert_subs("adj", Some(&["big"])).unwrap();
+
+        assert_eq!(db.render("[0%{adj} ]{noun@1}").unwrap().len(), 3);
+        assert!(db.render("[100%{adj} ]cat").unwrap() == "big cat");
+        assert_eq!(db.render("[0%a [b] ]").unwrap().len(), 0);
+        assert!(db.render("a [b").unwrap() == "a [b");
+
+        let mut unique = db.generate_unique("[{adj} ]{noun}", 4).unwrap();
+        unique.sort();
+        assert_eq!(unique, vec!["big cat", "big dog", "cat", "dog"]);
+        assert!(matches!(
+            db.generate_unique("[{adj} ]{noun}", 5),
+            Err(Error::NotEnoughUniqueOutputs { found: 4, .. })
+        ));
+
+        db.insert_pattern("phrase", "[30%{adj} ]{noun} [a [b]")
+            .unwrap();
+        let report = db.rename_template_propagating("adj", "size").unwrap();
+        assert_eq!(report.patterns, vec!["phrase"]);
+        assert_eq!(
+            db.get_pattern("phrase").unwrap().unwrap(),
+            "[30%{size} ]{noun} [a [b]"
+        );
+
+        assert!(db.render(r"\[note\] [100%\[{size}\]]").unwrap() == "[note] [big]");
+        assert!(db.render(r"a\b").unwrap() == r"a\b");
+        assert!(db.render(r"a\\[100%b]\\").unwrap() == r"a\b\");
+        db.insert_pattern("note", r"\[note\] [050%{size}]").unwrap();
+        db.rename_template_propagating("size", "adj").unwrap();
+        assert_eq!(
+            db.get_pattern("note").unwrap().unwrap(),
+            r"\[note\] [050%{adj}]"
+        );
+    }
+
+    #[test]
+    fn upgrade_escapes_patterns_stored_before_the_syntax() {
+        let mut db = TemplateDatabase::from_path("test70.db").unwrap();
+
+        db.clear().unwrap();
+        db.insert_subs("noun", Some(&["cat"])).unwrap();
+        db.insert_pattern("old", r"a [b] (c|d) \[ {noun}").unwrap();
+        db.db.execute_batch("PRAGMA user_version = 7;").unwrap();
+        drop(db);
+
+        let db = TemplateDatabase::from_path("test70.db").unwrap();
+        assert_eq!(
+            db.get_pattern("old").unwrap().unwrap(),
+            r"a \[b\] \(c\|d\) \\\[ {noun}"
+        );
+        assert!(db.render_pattern("old").unwrap() == r"a [b] (c|d) \[ cat");
+    }
+
+    #[test]
+    fn alternation_groups_in_patterns() {
+        let mut db = TemplateDatabase::from_path("test52.db").unwrap();
+
+        db.clear().unwrap();
+        db.insert_subs("noun", Some(&["cat", "dog"])).unwrap();
+
+        for _ in 0..20 {
+            let line = db.render("(a|the) {noun}").unwrap();
+            assert!(line.starts_with("a ") || line.starts_with("the "));
+        }
+        assert!(db.render("(maybe) (x").unwrap() == "(maybe) (x");
+
+        let mut unique = db.generate_unique("(a|the|[0%x]) {noun}", 6).unwrap();
+        unique.sort();
+        assert_eq!(
+            unique,
+            vec![" cat", " dog", "a cat", "a dog", "the cat", "the dog"]
+        );
+
+        db.insert_pattern("phrase", "(a|the ({noun}|x)) [y|z]")
+            .unwrap();
+        let report = db.rename_template_propagating("noun", "thing").unwrap();
+        assert_eq!(report.patterns, vec!["phrase"]);
+        assert_eq!(
+            db.get_pattern("phrase").unwrap().unwrap(),
+            "(a|the ({thing}|x)) [y|z]"
+        );
+
+        db.pin_sub("thing", "cat");
+        assert!(db.render("{thing} (plural)").unwrap() == "cat (plural)");
+        assert!(db.render(r"\(yes\|no\) (a\|b) \|").unwrap() == "(yes|no) (a|b) |");
+        db.insert_pattern("answer", r"{thing} \(yes\|no\)").unwrap();
+        db.rename_template_propagating("thing", "noun").unwrap();
+        assert_eq!(
+            db.get_pattern("answer").unwrap().unwrap(),
+            r"{noun} \(yes\|no\)"
+        );
+    }
+
+    #[test]
+    fn pinned_templates_return_fixed_values() {
+        let mut db = TemplateDatabase::from_path("test53.db").unwrap();
+
+        db.clear().unwrap();
+        db.insert_subs("noun", Some(NOUNS)).unwrap();
+        db.insert_subs("verb", Some(&["runs", "jumps"])).unwrap();
+
+        db.pin_sub("Noun", "unicorn");
+        let mut cache = TemplateCache::load(&db).unwrap();
+        for _ in 0..10 {
+            assert_eq!(db.get_random_subs("noun").unwrap(), "unicorn");
+            assert_eq!(db.template("noun").unwrap().random().unwrap(), "unicorn");
+            assert_eq!(cache.get_random_sub("noun"), Some("unicorn"));
+            assert_eq!(
+                db.random_sub("noun", &mut Rng::new(1)).unwrap(),
+                Some("unicorn".to_string())
+            );
+        }
+        assert_eq!(
+            db.get_random_picks(&[("noun", 3), ("verb", 0)]).unwrap(),
+            vec![vec!["unicorn".to_string()], vec![]]
+        );
+        assert!(db.render("the {noun} [100%{noun@1}]").unwrap() == "the unicorn unicorn");
+
+        db.pin_sub("verb", "sleeps");
+        let vars = HashMap::from([("verb", "flies")]);
+        assert_eq!(
+            db.render_with("{noun} {verb}", &vars).unwrap(),
+            "unicorn flies"
+        );
+        assert_eq!(db.render("{noun} {verb}").unwrap(), "unicorn sleeps");
+
+        assert!(db.unpin_sub("NOUN"));
+        assert!(!db.unpin_sub("noun"));
+        assert_ne!(db.render("{noun}").unwrap(), "unicorn");
+        db.clear_pins();
+        assert_ne!(db.get_random_subs("verb").unwrap(), "sleeps");
+    }
+
+    #[test]
+    fn sqlite_errors_carry_operation_context() {
+        let mut db = TemplateDatabase::from_path("test54.db").unwrap();
+
+        db.clear().unwrap();
+
+        let err = db.remove_sub("ghost", "cat").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "remove_sub failed for template 'ghost' value 'cat': {}",
+                rusqlite::Error::QueryReturnedNoRows
+            )
+        );
+        assert!(matches!(
+            err.sqlite(),
+            Some(rusqlite::Error::QueryReturnedNoRows)
+        ));
+        assert!(std::error::Error::source(&err).is_some());
+
+        db.insert_sub("noun", "cat").unwrap();
+        let err = db
+            .merge_templates("ghost", "noun", ConflictPolicy::Skip)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Context {
+                operation: "merge_templates",
+                ..
+            }
+        ));
+        let mut other = TemplateDatabase::from_path("test54.db").unwrap();
+        assert!(matches!(
+            db.copy_template_to("ghost", &mut other, ConflictPolicy::Skip),
+            Err(Error::Context {
+                operation: "copy_template_to",
+                ..
+            })
+        ));
+
+        db.lock_template("noun").unwrap();
+        assert!(matches!(
+            db.insert_sub("noun", "dog"),
+            Err(Error::TemplateLocked(_))
+        ));
+        db.unlock_template("noun").unwrap();
+    }
+
+    #[test]
+    fn remove_subs_matching_a_predicate() {
+        let mut db = TemplateDatabase::from_path("test55.db").unwrap();
+
+        db.clear().unwrap();
+        db.insert_subs("noun", Some(&["cat", "r2d2", "c3po", "Catalog", "dog"]))
+            .unwrap();
+
+        let has_digit = |x: &str| x.contains(|c: char| c.is_ascii_digit());
+        let preview = db
+            .remove_subs_where_dry_run("noun", SubPredicate::matches(has_digit))
+            .unwrap();
+        assert_eq!(preview.pruned, vec!["c3po", "r2d2"]);
+        assert_eq!(preview.remaining, 3);
+        assert_eq!(db.get_subs("noun").unwrap().len(), 5);
+
+        let report = db
+            .remove_subs_where("noun", SubPredicate::matches(has_digit))
+            .unwrap();
+        assert_eq!(report, preview);
+
+        let report = db
+            .remove_subs_where("noun", SubPredicate::like("cat%"))
+            .unwrap();
+        assert_eq!(report.pruned, vec!["cat", "Catalog"]);
+        assert_eq!(db.get_subs("noun").unwrap(), vec!["dog"]);
+
+        db.lock_template("noun").unwrap();
+        assert!(matches!(
+            db.remove_subs_where("noun", SubPredicate::like("%")),
+            Err(Error::TemplateLocked(_))
+        ));
+        db.unlock_template("noun").unwrap();
+    }
+
+    #[test]
+    fn copy_template_between_databases() {
+        let mut staging = TemplateDatabase::from_path("test56.db").unwrap();
+        let mut production = TemplateDatabase::from_path("test57.db").unwrap();
+
+        staging.clear().unwrap();
+        production.clear().unwrap();
+        staging
+            .upsert_subs(
+                "Noun",
+                &[
+                    SubEntry::new("cat").weight(3).tags(&["animal"]),
+                    SubEntry::new("cup").metadata("{\"kind\":\"object\"}"),
+                ],
+            )
+            .unwrap();
+        production
+            .upsert_subs("noun", &[SubEntry::new("CAT"), SubEntry::new("dog")])
+            .unwrap();
+
+        let report = staging
+            .copy_template_to("noun", &mut production, ConflictPolicy::MergeMetadata)
+            .unwrap();
+        assert_eq!((report.inserted, report.overwritten), (1, 1));
+        assert_eq!(
+            production.get_subs("noun").unwrap(),
+            vec!["CAT", "cup", "dog"]
+        );
+
+        let cat = production.get_sub_detail("noun", "cat").unwrap().unwrap();
+        assert_eq!((cat.weight, cat.tags), (3, vec!["animal".to_string()]));
+        let cup = production.get_sub_detail("noun", "cup").unwrap().unwrap();
+        assert_eq!(cup.metadata.as_deref(), Some("{\"kind\":\"object\"}"));
+
+        assert!(staging
+            .copy_template_to("verb", &mut production, ConflictPolicy::Skip)
+            .is_err());
+    }
+
+    #[test]
+    fn conflict_policies_apply_to_every_importer() {
+        let mut db = TemplateDatabase::from_path("test66.db").unwrap();
+        let dir = std::path::Path::new("test66_wordlists");
+        let _ = std::fs::remove_dir_all(dir);
+
+        db.clear().unwrap();
+        db.upsert_subs("noun", &[SubEntry::new("cat").weight(2).tags(&["pet"])])
+            .unwrap();
+
+        let zoo = Pack::new("zoo", "1.0").template(
+            "noun",
+            &[
+                SubEntry::new("CAT").weight(5).tags(&["wild"]),
+                SubEntry::new("owl"),
+            ],
+        );
+        let report = db
+            .install_pack(&zoo, ConflictPolicy::MergeMetadata)
+            .unwrap();
+        assert_eq!((report.inserted, report.overwritten), (1, 1));
+        let cat = db.get_sub_detail("noun", "cat").unwrap().unwrap();
+        assert_eq!((cat.value.as_str(), cat.weight), ("cat", 5));
+        assert_eq!(cat.tags, vec!["pet", "wild"]);
+        db.uninstall_pack("zoo").unwrap();
+        assert_eq!(db.get_subs("noun").unwrap(), vec!["cat"]);
+
+        let zoo = Pack::new("zoo", "1.0").template("noun", &[SubEntry::new("CAT").tags(&["wild"])]);
+        db.install_pack(&zoo, ConflictPolicy::Overwrite).unwrap();
+        let cat = db.get_sub_detail("noun", "cat").unwrap().unwrap();
+        assert_eq!((cat.value.as_str(), cat.weight), ("CAT", 5));
+        assert_eq!(cat.tags, vec!["wild"]);
+        db.uninstall_pack("zoo").unwrap();
+        assert!(matches!(
+            db.install_pack(&zoo, ConflictPolicy::Error),
+            Err(Error::Conflict { .. })
+        ));
+
+        db.export_wordlists(dir).unwrap();
+        assert!(matches!(
+            db.import_wordlists(dir, ConflictPolicy::Error),
+            Err(Error::Conflict { .. })
+        ));
+        let report = db.import_wordlists(dir, ConflictPolicy::Skip).unwrap();
+        assert_eq!((report.inserted, report.skipped), (0, 1));
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let mut dump = Vec::new();
+        db.export_full(&mut dump).unwrap();
+        let mut dump = String::from_utf8(dump).unwrap();
+        let line = dump
+            .lines()
+            .find(|x| x.starts_with("substitute\t"))
+            .unwrap();
+        let mut fields: Vec<&str> = line.split('\t').collect();
+        fields[1] = "999";
+        fields[3] = "cat";
+        fields[4] = "8";
+        dump.push_str(&format!("{}\ntag\t999\tbig\n", fields.join("\t")));
+
+        db.clear().unwrap();
+        assert!(matches!(
+            db.import_full(dump.as_bytes(), ConflictPolicy::Error),
+            Err(Error::Conflict { .. })
+        ));
+        db.import_full(dump.as_bytes(), ConflictPolicy::Skip)
+            .unwrap();
+        let cat = db.get_sub_detail("noun", "cat").unwrap().unwrap();
+        assert_eq!((cat.value.as_str(), cat.weight), ("CAT", 5));
+        assert_eq!(cat.tags, vec!["wild"]);
+
+        db.clear().unwrap();
+        db.import_full(dump.as_bytes(), ConflictPolicy::MergeMetadata)
+            .unwrap();
+        let cat = db.get_sub_detail("noun", "cat").unwrap().unwrap();
+        assert_eq!((cat.value.as_str(), cat.weight), ("CAT", 8));
+        assert_eq!(cat.tags, vec!["big", "wild"]);
+
+        db.clear().unwrap();
+        db.import_full(dump.as_bytes(), ConflictPolicy::Overwrite)
+            .unwrap();
+        let cat = db.get_sub_detail("noun", "cat").unwrap().unwrap();
+        assert_eq!((cat.value.as_str(), cat.weight), ("cat", 8));
+        assert_eq!(cat.tags, vec!["big"]);
+    }
+
+    #[test]
+    fn batched_writes_share_one_transaction() {
+        let mut db = TemplateDatabase::from_path("test58.db").unwrap();
+        let other = TemplateDatabase::from_path("test58.db").unwrap();
+
+        db.clear().unwrap();
+        db.insert_sub("noun", "cat").unwrap();
+        db.insert_sub("locked", "y").unwrap();
+        db.lock_template("locked").unwrap();
+
+        db.begin_batch().unwrap();
+        assert!(db.is_batching());
+        for noun in NOUNS {
+            db.insert_sub("noun", noun).unwrap();
+        }
+        assert!(matches!(
+            db.insert_sub("locked", "x"),
+            Err(Error::TemplateLocked(_))
+        ));
+        assert!(db.begin_batch().is_err());
+        assert_eq!(db.get_subs("noun").unwrap().len(), NOUNS.len());
+        assert_eq!(other.get_subs("noun").unwrap(), vec!["cat"]);
+        db.end_batch().unwrap();
+
+        assert!(!db.is_batching());
+        assert_eq!(other.get_subs("noun").unwrap().len(), NOUNS.len());
+
+        db.begin_batch().unwrap();
+        db.remove_template("noun").unwrap();
+        db.cancel_batch().unwrap();
+        assert_eq!(db.get_subs("noun").unwrap().len(), NOUNS.len());
+        assert!(db.end_batch().is_err());
+
+        db.unlock_template("locked").unwrap();
+    }
+
+    #[test]
+    fn health_check_reports_schema_and_failures() {
+        let db = TemplateDatabase::from_path("test59.db").unwrap();
+
+        let status = db.health_check();
+        assert!(status.healthy);
+        assert_eq!(status.schema_version, Some(DATABASE_VERSION));
+        assert_eq!(status.error, None);
+
+        db.db.execute_batch("PRAGMA user_version = 99;").unwrap();
+        let status = db.health_check();
+        assert!(!status.healthy);
+        assert_eq!(status.schema_version, Some(99));
+        db.db
+            .execute_batch(&format!("PRAGMA user_version = {};", DATABASE_VERSION))
+            .unwrap();
+
+        db.db
+            .execute_batch("ALTER TABLE templates RENAME TO gone;")
+            .unwrap();
+        let status = db.health_check();
+        assert!(!status.healthy);
+        assert!(status.error.is_some());
+        db.db
+            .execute_batch("ALTER TABLE gone RENAME TO templates;")
+            .unwrap();
+        assert!(db.health_check().healthy);
+    }
+
+    #[test]
+    fn filter_substitutes_by_word_count() {
+        let mut db = TemplateDatabase::from_path("test60.db").unwrap();
+
+        db.clear().unwrap();
+        db.insert_subs(
+            "place",
+            Some(&["France", "United States", " Peru ", "Isle of Man"]),
+        )
+        .unwrap();
+        db.insert_subs("place", Some(&["new  york", "tab\tsep", "line\nbreak"]))
+            .unwrap();
+
+        assert_eq!(
+            db.get_subs_by_word_count("place", 1).unwrap(),
+            vec![" Peru ", "France"]
+        );
+        assert_eq!(
+            db.get_subs_by_word_count("place", 3).unwrap(),
+            vec!["Isle of Man"]
+        );
+        assert!(db.get_subs_by_word_count("place", 4).unwrap().is_empty());
+        assert!(db.get_subs_by_word_count("planet", 1).is_err());
+        assert_eq!(
+            db.get_subs_by_word_count("place", 2).unwrap(),
+            vec!["line\nbreak", "new  york", "tab\tsep", "United States"]
+        );
+        assert_eq!(
+            db.query().template("place").word_count(2).count().unwrap(),
+            4
+        );
+        assert_eq!(
+            db.sub_word_count_histogram("place").unwrap(),
+            vec![(1, 2), (2, 4), (3, 1)]
+        );
+
+        for _ in 0..20 {
+            let pick = db.get_random_subs_by_word_count("place", 1).unwrap();
+            assert!(pick == "France" || pick == " Peru ");
+        }
+        assert_eq!(db.get_random_subs_by_word_count("place", 5).unwrap(), "");
+
+        db.insert_subs("city", Some(&["new  york", "Paris"]))
+            .unwrap();
+        for _ in 0..10 {
+            assert_eq!(
+                db.get_random_subs_by_word_count("city", 2).unwrap(),
+                "new  york"
+            );
+        }
+    }
+
+    #[test]
+    fn compact_reclaims_deleted_space() {
+        let mut db = TemplateDatabase::from_path("test61.db").unwrap();
+
+        db.clear().unwrap();
+        let values: Vec<String> = (0..5000).map(|x| format!("archived value {}", x)).collect();
+        let values: Vec<&str> = values.iter().map(|x| x.as_str()).collect();
+        db.insert_subs("archive", Some(&values)).unwrap();
+        db.remove_template("archive").unwrap();
+
+        let report = db.compact().unwrap();
+        assert!(report.free_pages_before > 0);
+        assert!(report.reclaimed_pages > 0);
+        assert!(report.size_after < report.size_before);
+        assert_eq!(
+            std::fs::metadata("test61.db").unwrap().len(),
+            report.size_after
+        );
+
+        db.begin_batch().unwrap();
+        assert!(db.compact().is_err());
+        db.end_batch().unwrap();
+    }
+
+    #[test]
+    fn huge_weights_do_not_overflow() {
+        let mut db = TemplateDatabase::from_path("test62.db").unwrap();
+
+        db.clear().unwrap();
+        db.upsert_subs(
+            "noun",
+            &[
+                SubEntry::new("cat").weight(i64::MAX),
+                SubEntry::new("dog").weight(i64::MAX),
+                SubEntry::new("ape").weight(i64::MAX),
+                SubEntry::new("bat").weight(0),
+            ],
+        )
+        .unwrap();
+
+        for _ in 0..50 {
+            let pick = db.get_random_subs("noun").unwrap();
+            assert!(["cat", "dog", "ape"].contains(&pick.as_str()));
+        }
+    }
+
+    #[test]
+    fn cached_backend_honours_weights() {
+        let mut db = TemplateDatabase::from_path("test63.db").unwrap();
+
+        db.clear().unwrap();
+        db.upsert_subs(
+            "noun",
+            &[SubEntry::new("cat"), SubEntry::new("dog").weight(0)],
+        )
+        .unwrap();
+
+        let cached = CachedBackend::new(db, 4);
+        let mut rng = Rng::new(7);
+        for _ in 0..100 {
+            assert_eq!(
+                cached.random_sub("noun", &mut rng).unwrap(),
+                Some("cat".to_string())
+            );
+        }
+        assert_eq!(cached.subs("noun").unwrap().unwrap(), vec!["cat", "dog"]);
+        assert_eq!(
+            cached.weighted_subs("noun").unwrap().unwrap(),
+            vec![("cat".to_string(), 1)]
+        );
+
+        let mut db = cached.into_inner();
+        db.pin_sub("noun", "unicorn");
+        let cached = CachedBackend::new(db, 4);
+        assert_eq!(
+            cached.random_sub("noun", &mut rng).unwrap(),
+            Some("unicorn".to_string())
+        );
+    }
 }
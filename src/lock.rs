@@ -0,0 +1,87 @@
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::error::with_context;
+use crate::{Error, Result, TemplateDatabase};
+
+impl TemplateDatabase {
+    // Fails with `Error::TemplateLocked` when the template exists and is locked. Missing
+    // templates pass, so callers keep their usual behavior for them.
+    pub(crate) fn execute_check_unlocked(db: &Connection, template: &str) -> Result<()> {
+        let locked: Option<(String, bool)> = db
+            .query_row(
+                "SELECT name, locked FROM templates WHERE name = ?1",
+                [template],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        match locked {
+            Some((name, true)) => Err(Error::TemplateLocked(name)),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn execute_check_unlocked_id(db: &Connection, template_id: i64) -> Result<()> {
+        let locked: Option<(String, bool)> = db
+            .query_row(
+                "SELECT name, locked FROM templates WHERE id = ?1",
+                [template_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        match locked {
+            Some((name, true)) => Err(Error::TemplateLocked(name)),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn execute_check_none_locked(db: &Connection) -> Result<()> {
+        let locked: Option<String> = db
+            .query_row(
+                "SELECT name FROM templates WHERE locked ORDER BY LOWER(name) LIMIT 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match locked {
+            Some(name) => Err(Error::TemplateLocked(name)),
+            None => Ok(()),
+        }
+    }
+
+    // Locked templates refuse every change to their name or substitutes until unlocked.
+    // Returns false when the template does not exist.
+    pub fn lock_template(&mut self, template: &str) -> Result<bool> {
+        with_context("lock_template", Some(template), None, || {
+            let result = self.db.execute(
+                "UPDATE templates SET locked = 1 WHERE name = ?1",
+                [template],
+            )?;
+            Ok(result > 0)
+        })
+    }
+
+    pub fn unlock_template(&mut self, template: &str) -> Result<bool> {
+        with_context("unlock_template", Some(template), None, || {
+            let result = self.db.execute(
+                "UPDATE templates SET locked = 0 WHERE name = ?1",
+                [template],
+            )?;
+            Ok(result > 0)
+        })
+    }
+
+    pub fn is_template_locked(&self, template: &str) -> rusqlite::Result<bool> {
+        let locked: Option<bool> = self
+            .db
+            .query_row(
+                "SELECT locked FROM templates WHERE name = ?1",
+                [template],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(locked.unwrap_or(false))
+    }
+}
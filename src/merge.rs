@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::error::with_context;
+use crate::{ConflictPolicy, Error, Result, SubEntry, TemplateDatabase};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeCollision {
+    // Value as stored in the source template.
+    pub source: String,
+    // Value already stored in the target template, equal to `source` ignoring case.
+    pub target: String,
+}
+
+// What merging one template into another does, computed without changing anything.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MergePlan {
+    pub policy: ConflictPolicy,
+    // Source substitutes that move to the target unchanged.
+    pub moved: Vec<String>,
+    // Source substitutes already in the target. `Skip` drops the source copy, `Overwrite`
+    // keeps the source spelling and attributes, `MergeMetadata` merges the source attributes
+    // into the target copy and `Error` makes the merge fail.
+    pub collisions: Vec<MergeCollision>,
+    // Names of the stored patterns whose `{source}` placeholders are pointed at the target.
+    pub patterns: Vec<String>,
+    pub source_count: usize,
+    pub target_count: usize,
+    // Number of substitutes in the target after the merge.
+    pub merged_count: usize,
+}
+
+fn template_id(db: &Connection, name: &str) -> rusqlite::Result<(i64, String)> {
+    db.query_row(
+        "SELECT id, name FROM templates WHERE name = ?1",
+        [name],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()?
+    .ok_or(rusqlite::Error::QueryReturnedNoRows)
+}
+
+fn sub_names(db: &Connection, template_id: i64) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = db.prepare_cached(
+        "SELECT name FROM substitutes WHERE template_id = ?1 ORDER BY LOWER(name) ASC",
+    )?;
+    let names = stmt.query_map([template_id], |row| row.get(0))?;
+    names.collect()
+}
+
+impl TemplateDatabase {
+    fn execute_plan_merge(
+        db: &Connection,
+        source: &str,
+        target: &str,
+        policy: ConflictPolicy,
+    ) -> rusqlite::Result<MergePlan> {
+        let (source_id, _) = template_id(db, source)?;
+        let (target_id, _) = template_id(db, target)?;
+        let target_subs = sub_names(db, target_id)?;
+
+        let mut plan = MergePlan {
+            policy,
+            target_count: target_subs.len(),
+            merged_count: target_subs.len(),
+            ..MergePlan::default()
+        };
+        if source_id == target_id {
+            plan.source_count = target_subs.len();
+            return Ok(plan);
+        }
+
+        let existing: HashMap<String, String> = target_subs
+            .into_iter()
+            .map(|x| (x.to_ascii_lowercase(), x))
+            .collect();
+
+        for sub in sub_names(db, source_id)? {
+            plan.source_count += 1;
+            match existing.get(&sub.to_ascii_lowercase()) {
+                Some(target) => plan.collisions.push(MergeCollision {
+                    source: sub,
+                    target: target.clone(),
+                }),
+                None => plan.moved.push(sub),
+            }
+        }
+        plan.merged_count += plan.moved.len();
+
+        plan.patterns = Self::execute_find_referencing_patterns(db, source)?
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect();
+
+        Ok(plan)
+    }
+
+    // Previews `merge_templates` so a caller can review the outcome before committing to it.
+    pub fn plan_merge_templates(
+        &self,
+        source: &str,
+        target: &str,
+        policy: ConflictPolicy,
+    ) -> rusqlite::Result<MergePlan> {
+        let tx = self.read_transaction()?;
+        let plan = Self::execute_plan_merge(&tx, source, target, policy)?;
+        tx.commit()?;
+        Ok(plan)
+    }
+
+    // Moves every substitute of `source` into `target`, points patterns using `{source}` at
+    // `target` and removes `source`, all in one transaction. Moved substitutes keep their
+    // weight, metadata and usage. Returns the plan that was carried out.
+    pub fn merge_templates(
+        &mut self,
+        source: &str,
+        target: &str,
+        policy: ConflictPolicy,
+    ) -> Result<MergePlan> {
+        with_context("merge_templates", Some(source), Some(target), || {
+            let tx = self.db.savepoint()?;
+            Self::execute_check_unlocked(&tx, source)?;
+            Self::execute_check_unlocked(&tx, target)?;
+
+            let plan = Self::execute_plan_merge(&tx, source, target, policy)?;
+            let (source_id, _) = template_id(&tx, source)?;
+            let (target_id, target_name) = template_id(&tx, target)?;
+            if source_id == target_id {
+                return Ok(plan);
+            }
+
+            if let (Some(collision), ConflictPolicy::Error) = (plan.collisions.first(), policy) {
+                return Err(Error::Conflict {
+                    template: target_name,
+                    value: collision.source.clone(),
+                });
+            }
+
+            for collision in &plan.collisions {
+                if policy != ConflictPolicy::Skip {
+                    let (id, weight, metadata): (i64, i64, Option<String>) = tx.query_row(
+                        "SELECT id, weight, metadata FROM substitutes
+                         WHERE template_id = ?1 AND name = ?2",
+                        (source_id, &collision.source),
+                        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                    )?;
+                    let tags = Self::execute_get_tags(&tx, id)?;
+                    let tags: Vec<&str> = tags.iter().map(|x| x.as_str()).collect();
+                    let entry = SubEntry {
+                        value: &collision.source,
+                        weight: Some(weight),
+                        tags: Some(&tags),
+                        metadata: metadata.as_deref(),
+                    };
+                    Self::execute_insert_entry_with_policy(
+                        &tx,
+                        &target_name,
+                        &target_id.to_string(),
+                        &entry,
+                        policy,
+                    )?;
+                }
+                tx.execute(
+                    "DELETE FROM substitutes WHERE template_id = ?1 AND name = ?2",
+                    (source_id, &collision.source),
+                )?;
+            }
+
+            tx.execute(
+                "UPDATE substitutes SET template_id = ?1 WHERE template_id = ?2",
+                [target_id, source_id],
+            )?;
+            Self::execute_rewrite_placeholders(&tx, source, &target_name)?;
+            tx.execute("DELETE FROM templates WHERE id = ?1", [source_id])?;
+
+            tx.commit()?;
+
+            Ok(plan)
+        })
+    }
+}
@@ -0,0 +1,332 @@
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::error::with_context;
+use crate::import::InsertOutcome;
+use crate::{ConflictPolicy, Error, ImportReport, Result, SubEntry, TemplateDatabase};
+
+// A named, versioned bundle of templates, substitutes and patterns, installed and
+// uninstalled as a unit. Uninstalling only removes what the pack added, values that were
+// already present when it was installed stay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pack<'a> {
+    pub name: &'a str,
+    pub version: &'a str,
+    pub templates: Vec<(&'a str, Vec<SubEntry<'a>>)>,
+    pub patterns: Vec<(&'a str, &'a str)>,
+    // Other packs that must be installed first, with a version requirement for each.
+    pub dependencies: Vec<(&'a str, &'a str)>,
+}
+
+// Versions are `major.minor.patch`, missing parts count as zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Version(u64, u64, u64);
+
+impl Version {
+    fn parse(text: &str) -> Option<Version> {
+        let mut parts = text.trim().split('.');
+        let mut next = |required: bool| match parts.next() {
+            Some(part) => part.parse().ok(),
+            None if required => None,
+            None => Some(0),
+        };
+        let version = Version(next(true)?, next(false)?, next(false)?);
+        match parts.next() {
+            Some(_) => None,
+            None => Some(version),
+        }
+    }
+}
+
+// Checks `version` against a comma separated list of comparators (`=`, `>`, `>=`, `<`,
+// `<=`, `~`, `^`), where a bare version means `^` as in Cargo and `*` matches anything.
+// Returns `None` when the requirement cannot be parsed.
+fn satisfies(version: Version, requirement: &str) -> Option<bool> {
+    let mut matches = true;
+
+    for comparator in requirement.split(',').map(str::trim) {
+        if comparator == "*" {
+            continue;
+        }
+
+        let (op, rest) = ["<=", ">=", "=", "<", ">", "~", "^"]
+            .iter()
+            .find_map(|op| comparator.strip_prefix(op).map(|rest| (*op, rest)))
+            .unwrap_or(("^", comparator));
+        let wanted = Version::parse(rest)?;
+        let parts = rest.trim().split('.').count();
+
+        matches &= match op {
+            "=" => version == wanted,
+            ">" => version > wanted,
+            ">=" => version >= wanted,
+            "<" => version < wanted,
+            "<=" => version <= wanted,
+            "~" if parts == 1 => version >= wanted && version.0 == wanted.0,
+            "~" => version >= wanted && (version.0, version.1) == (wanted.0, wanted.1),
+            _ if wanted.0 > 0 || parts == 1 => version >= wanted && version.0 == wanted.0,
+            _ if wanted.1 > 0 || parts == 2 => {
+                version >= wanted && (version.0, version.1) == (wanted.0, wanted.1)
+            }
+            _ => version == wanted,
+        };
+    }
+
+    Some(matches)
+}
+
+impl<'a> Pack<'a> {
+    pub fn new(name: &'a str, version: &'a str) -> Pack<'a> {
+        Pack {
+            name,
+            version,
+            templates: Vec::new(),
+            patterns: Vec::new(),
+            dependencies: Vec::new(),
+        }
+    }
+
+    pub fn template(mut self, name: &'a str, entries: &[SubEntry<'a>]) -> Self {
+        self.templates.push((name, entries.to_vec()));
+        self
+    }
+
+    pub fn pattern(mut self, name: &'a str, pattern: &'a str) -> Self {
+        self.patterns.push((name, pattern));
+        self
+    }
+
+    pub fn depends_on(mut self, pack: &'a str, requirement: &'a str) -> Self {
+        self.dependencies.push((pack, requirement));
+        self
+    }
+}
+
+impl TemplateDatabase {
+    fn execute_record_pack_item(
+        tx: &Connection,
+        pack_id: i64,
+        kind: &str,
+        item_id: i64,
+    ) -> rusqlite::Result<()> {
+        tx.execute(
+            "INSERT INTO pack_contents (pack_id, kind, item_id) VALUES (?1, ?2, ?3)",
+            (pack_id, kind, item_id),
+        )?;
+        Ok(())
+    }
+
+    fn execute_find_pack(tx: &Connection, name: &str) -> rusqlite::Result<Option<i64>> {
+        tx.query_row("SELECT id FROM packs WHERE name = ?1", [name], |row| {
+            row.get(0)
+        })
+        .optional()
+    }
+
+    fn execute_check_dependencies(tx: &Connection, pack: &Pack) -> Result<()> {
+        for (dependency, requirement) in &pack.dependencies {
+            let installed: Option<String> = tx
+                .query_row(
+                    "SELECT version FROM packs WHERE name = ?1",
+                    [dependency],
+                    |row| row.get(0),
+                )
+                .optional()?;
+
+            let Some(installed) = installed else {
+                return Err(Error::MissingDependency {
+                    pack: pack.name.to_string(),
+                    dependency: dependency.to_string(),
+                });
+            };
+
+            let version = Version::parse(&installed)
+                .ok_or_else(|| Error::InvalidVersion(installed.clone()))?;
+            let compatible = satisfies(version, requirement)
+                .ok_or_else(|| Error::InvalidVersion(requirement.to_string()))?;
+
+            if !compatible {
+                return Err(Error::IncompatibleDependency {
+                    pack: pack.name.to_string(),
+                    dependency: dependency.to_string(),
+                    required: requirement.to_string(),
+                    installed,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Installs everything in the pack in one transaction. Substitutes that already exist are
+    // handled by `policy` and stay when the pack is uninstalled. A pattern name that is
+    // already taken fails the whole install, and so does a dependency that is missing or
+    // installed in an incompatible version.
+    pub fn install_pack(&mut self, pack: &Pack, policy: ConflictPolicy) -> Result<ImportReport> {
+        with_context("install_pack", None, Some(pack.name), || {
+            let tx = self.db.savepoint()?;
+
+            if Self::execute_find_pack(&tx, pack.name)?.is_some() {
+                return Err(Error::PackInstalled(pack.name.to_string()));
+            }
+            if Version::parse(pack.version).is_none() {
+                return Err(Error::InvalidVersion(pack.version.to_string()));
+            }
+            Self::execute_check_dependencies(&tx, pack)?;
+
+            tx.execute(
+                "INSERT INTO packs (name, version) VALUES (?1, ?2)",
+                [pack.name, pack.version],
+            )?;
+            let pack_id = tx.last_insert_rowid();
+
+            for (dependency, requirement) in &pack.dependencies {
+                tx.execute(
+                    "INSERT OR REPLACE INTO pack_dependencies (pack_id, dependency, requirement)
+                     VALUES (?1, ?2, ?3)",
+                    (pack_id, dependency, requirement),
+                )?;
+            }
+            let mut report = ImportReport::default();
+
+            for (template, entries) in &pack.templates {
+                Self::execute_check_unlocked(&tx, template)?;
+                if Self::execute_insert_template(&tx, template)? {
+                    Self::execute_record_pack_item(
+                        &tx,
+                        pack_id,
+                        "template",
+                        tx.last_insert_rowid(),
+                    )?;
+                    report.templates_created += 1;
+                }
+                let template_id = Self::find_template_id_with_transaction(&tx, template)?;
+
+                for entry in entries {
+                    let (outcome, id) = Self::execute_insert_entry_with_policy(
+                        &tx,
+                        template,
+                        &template_id,
+                        entry,
+                        policy,
+                    )?;
+                    if outcome == InsertOutcome::Inserted {
+                        Self::execute_record_pack_item(&tx, pack_id, "substitute", id)?;
+                    }
+                    report.record(outcome);
+                }
+            }
+
+            for (name, pattern) in &pack.patterns {
+                let result = tx.execute(
+                    "INSERT OR IGNORE INTO patterns (name, pattern) VALUES (?1, ?2)",
+                    [name, pattern],
+                )?;
+                if result == 0 {
+                    return Err(Error::PatternExists(name.to_string()));
+                }
+                Self::execute_record_pack_item(&tx, pack_id, "pattern", tx.last_insert_rowid())?;
+            }
+
+            tx.commit()?;
+
+            Ok(report)
+        })
+    }
+
+    // Removes the substitutes and patterns the pack added, then the templates it created
+    // unless something else has been added to them since. Packs that other installed packs
+    // depend on are refused with `Error::PackRequired`.
+    pub fn uninstall_pack(&mut self, name: &str) -> Result<bool> {
+        with_context("uninstall_pack", None, Some(name), || {
+            let tx = self.db.savepoint()?;
+
+            let Some(pack_id) = Self::execute_find_pack(&tx, name)? else {
+                return Ok(false);
+            };
+
+            let dependent: Option<String> = tx
+                .query_row(
+                    "SELECT packs.name
+                     FROM pack_dependencies
+                     JOIN packs ON packs.id = pack_dependencies.pack_id
+                     WHERE pack_dependencies.dependency = ?1
+                     ORDER BY LOWER(packs.name) LIMIT 1",
+                    [name],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if let Some(dependent) = dependent {
+                return Err(Error::PackRequired {
+                    pack: name.to_string(),
+                    dependent,
+                });
+            }
+
+            let locked: Option<String> = tx
+                .query_row(
+                    "SELECT name FROM templates
+                     WHERE locked AND (
+                         id IN (SELECT template_id FROM substitutes WHERE id IN (
+                             SELECT item_id FROM pack_contents
+                             WHERE pack_id = ?1 AND kind = 'substitute'))
+                         OR id IN (
+                             SELECT item_id FROM pack_contents
+                             WHERE pack_id = ?1 AND kind = 'template'))
+                     ORDER BY LOWER(name) LIMIT 1",
+                    [pack_id],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if let Some(template) = locked {
+                return Err(Error::TemplateLocked(template));
+            }
+
+            tx.execute(
+                "DELETE FROM substitutes WHERE id IN (
+                     SELECT item_id FROM pack_contents WHERE pack_id = ?1 AND kind = 'substitute')",
+                [pack_id],
+            )?;
+            tx.execute(
+                "DELETE FROM patterns WHERE id IN (
+                     SELECT item_id FROM pack_contents WHERE pack_id = ?1 AND kind = 'pattern')",
+                [pack_id],
+            )?;
+            tx.execute(
+                "DELETE FROM templates
+                 WHERE id IN (
+                     SELECT item_id FROM pack_contents WHERE pack_id = ?1 AND kind = 'template')
+                 AND NOT EXISTS (SELECT 1 FROM substitutes WHERE template_id = templates.id)",
+                [pack_id],
+            )?;
+            tx.execute("DELETE FROM pack_contents WHERE pack_id = ?1", [pack_id])?;
+            tx.execute(
+                "DELETE FROM pack_dependencies WHERE pack_id = ?1",
+                [pack_id],
+            )?;
+            tx.execute("DELETE FROM packs WHERE id = ?1", [pack_id])?;
+
+            tx.commit()?;
+
+            Ok(true)
+        })
+    }
+
+    pub fn get_packs(&self) -> Result<Vec<String>> {
+        let mut stmt = self.db.prepare(
+            "SELECT packs.name
+             FROM packs
+             ORDER BY LOWER(packs.name) ASC;",
+        )?;
+
+        let packs = stmt.query_map([], |row| row.get(0))?;
+
+        Ok(packs.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn get_pack_version(&self, name: &str) -> rusqlite::Result<Option<String>> {
+        self.db
+            .query_row("SELECT version FROM packs WHERE name = ?1", [name], |row| {
+                row.get(0)
+            })
+            .optional()
+    }
+}
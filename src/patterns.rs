@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::error::with_context;
+use crate::render::{parse_pattern, split_placeholder, visit_placeholders, write_pattern};
+use crate::{Error, Result, TemplateDatabase};
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RenameReport {
+    pub renamed: bool,
+    // Names of the stored patterns whose placeholders were rewritten.
+    pub patterns: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RemoveReport {
+    pub removed: bool,
+    // Names of the stored patterns deleted along with the template.
+    pub patterns: Vec<String>,
+}
+
+impl TemplateDatabase {
+    // Ids and names of the stored patterns with a `{template}` placeholder.
+    pub(crate) fn execute_find_referencing_patterns(
+        db: &Connection,
+        template: &str,
+    ) -> rusqlite::Result<Vec<(i64, String)>> {
+        let mut stmt = db.prepare("SELECT id, name, pattern FROM patterns ORDER BY LOWER(name)")?;
+        let mut rows = stmt.query([])?;
+        let mut referencing = Vec::new();
+
+        while let Some(row) = rows.next()? {
+            let pattern: String = row.get(2)?;
+            let mut placeholders = Vec::new();
+            visit_placeholders(&mut parse_pattern(&pattern), &mut |x| {
+                placeholders.push(x.clone());
+            });
+            let mut references = false;
+            for placeholder in &placeholders {
+                references |= split_placeholder(db, placeholder)?
+                    .0
+                    .eq_ignore_ascii_case(template);
+            }
+            if references {
+                referencing.push((row.get(0)?, row.get(1)?));
+            }
+        }
+
+        Ok(referencing)
+    }
+
+    pub fn insert_pattern(&mut self, name: &str, pattern: &str) -> Result<bool> {
+        with_context("insert_pattern", None, Some(name), || {
+            let result = self.db.execute(
+                "INSERT OR IGNORE INTO patterns (name, pattern) VALUES (?1, ?2)",
+                [name, pattern],
+            )?;
+            Ok(result > 0)
+        })
+    }
+
+    pub fn remove_pattern(&mut self, name: &str) -> Result<bool> {
+        with_context("remove_pattern", None, Some(name), || {
+            let result = self
+                .db
+                .execute("DELETE FROM patterns WHERE name = ?1", [name])?;
+            Ok(result > 0)
+        })
+    }
+
+    pub fn get_pattern(&self, name: &str) -> rusqlite::Result<Option<String>> {
+        self.db
+            .query_row(
+                "SELECT pattern FROM patterns WHERE name = ?1",
+                [name],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    pub fn get_patterns(&self) -> rusqlite::Result<Vec<String>> {
+        let mut stmt = self.db.prepare(
+            "SELECT patterns.name
+             FROM patterns
+             ORDER BY LOWER(patterns.name) ASC;",
+        )?;
+
+        let patterns = stmt.query_map([], |row| row.get(0))?;
+
+        patterns.collect()
+    }
+
+    pub fn render_pattern(&self, name: &str) -> rusqlite::Result<String> {
+        let pattern = self
+            .get_pattern(name)?
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+        self.render(&pattern)
+    }
+
+    // Removes a template unless a stored pattern still uses it, which fails with
+    // `Error::TemplateReferenced` naming those patterns. With `force` the patterns are
+    // deleted in the same transaction instead.
+    pub fn remove_template_checked(&mut self, template: &str, force: bool) -> Result<RemoveReport> {
+        with_context("remove_template_checked", Some(template), None, || {
+            let tx = self.db.savepoint()?;
+            Self::execute_check_unlocked(&tx, template)?;
+            let mut report = RemoveReport::default();
+
+            let template_id: Option<i64> = tx
+                .query_row(
+                    "SELECT id FROM templates WHERE name = ?1",
+                    [template],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let Some(template_id) = template_id else {
+                return Ok(report);
+            };
+
+            let referencing = Self::execute_find_referencing_patterns(&tx, template)?;
+            if !referencing.is_empty() && !force {
+                return Err(Error::TemplateReferenced {
+                    template: template.to_string(),
+                    patterns: referencing.into_iter().map(|(_, name)| name).collect(),
+                });
+            }
+
+            for (id, name) in referencing {
+                tx.execute("DELETE FROM patterns WHERE id = ?1", [id])?;
+                report.patterns.push(name);
+            }
+
+            tx.execute(
+                "DELETE FROM substitutes WHERE template_id = ?1",
+                [template_id],
+            )?;
+            let result = tx.execute("DELETE FROM templates WHERE id = ?1", [template_id])?;
+            report.removed = result > 0;
+
+            tx.commit()?;
+
+            Ok(report)
+        })
+    }
+
+    // Points every `{old_template}` or `{old_template@tag}` placeholder in stored patterns at
+    // `new_template` and returns the names of the patterns that changed.
+    pub(crate) fn execute_rewrite_placeholders(
+        db: &Connection,
+        old_template: &str,
+        new_template: &str,
+    ) -> rusqlite::Result<Vec<String>> {
+        let stored: Vec<(i64, String, String)> = {
+            let mut stmt = db.prepare("SELECT id, name, pattern FROM patterns ORDER BY id")?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        let mut rewritten = Vec::new();
+
+        for (id, name, pattern) in stored {
+            let mut segments = parse_pattern(&pattern);
+            let mut placeholders = Vec::new();
+            visit_placeholders(&mut segments, &mut |x| placeholders.push(x.clone()));
+
+            // `old_template` may already be gone, so a placeholder equal to it is never split.
+            let mut replacements = HashMap::new();
+            for placeholder in placeholders {
+                let replacement = if placeholder.eq_ignore_ascii_case(old_template) {
+                    new_template.to_string()
+                } else {
+                    match split_placeholder(db, &placeholder)? {
+                        (template, Some(tag)) if template.eq_ignore_ascii_case(old_template) => {
+                            format!("{}@{}", new_template, tag)
+                        }
+                        _ => continue,
+                    }
+                };
+                replacements.insert(placeholder, replacement);
+            }
+
+            if !replacements.is_empty() {
+                visit_placeholders(&mut segments, &mut |placeholder| {
+                    if let Some(replacement) = replacements.get(placeholder.as_str()) {
+                        *placeholder = replacement.clone();
+                    }
+                });
+                db.execute(
+                    "UPDATE patterns SET pattern = ?1 WHERE id = ?2",
+                    (write_pattern(&segments), id),
+                )?;
+                rewritten.push(name);
+            }
+        }
+
+        Ok(rewritten)
+    }
+
+    // Renames a template and rewrites every `{old_template}` placeholder in stored patterns
+    // in the same transaction. Patterns are left alone when the template does not exist.
+    pub fn rename_template_propagating(
+        &mut self,
+        old_template: &str,
+        new_template: &str,
+    ) -> Result<RenameReport> {
+        with_context(
+            "rename_template_propagating",
+            Some(old_template),
+            Some(new_template),
+            || {
+                let tx = self.db.savepoint()?;
+                Self::execute_check_unlocked(&tx, old_template)?;
+                let mut report = RenameReport::default();
+
+                let result = tx.execute(
+                    "UPDATE templates SET name = ?1 WHERE name = ?2",
+                    [new_template, old_template],
+                )?;
+                report.renamed = result > 0;
+
+                if report.renamed {
+                    report.patterns =
+                        Self::execute_rewrite_placeholders(&tx, old_template, new_template)?;
+                }
+
+                tx.commit()?;
+
+                Ok(report)
+            },
+        )
+    }
+}
@@ -0,0 +1,27 @@
+use crate::TemplateDatabase;
+
+impl TemplateDatabase {
+    // Test support: while a template is pinned, `get_random_subs`, `get_random_picks`,
+    // `get_random_subs_by_word_count`, `Template::random`, `TemplateSource::random_sub`,
+    // every render and a `TemplateCache` refreshed from this database return `value` for it,
+    // whatever is stored. Pins live on this connection only and are never written to the
+    // database.
+    pub fn pin_sub(&mut self, template: &str, value: &str) {
+        self.pins
+            .insert(template.to_ascii_lowercase(), value.to_string());
+    }
+
+    pub fn unpin_sub(&mut self, template: &str) -> bool {
+        self.pins.remove(&template.to_ascii_lowercase()).is_some()
+    }
+
+    pub fn clear_pins(&mut self) {
+        self.pins.clear();
+    }
+
+    pub(crate) fn pinned(&self, template: &str) -> Option<&str> {
+        self.pins
+            .get(&template.to_ascii_lowercase())
+            .map(|x| x.as_str())
+    }
+}
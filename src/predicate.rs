@@ -0,0 +1,134 @@
+use std::fmt;
+
+use rusqlite::Connection;
+
+use crate::error::with_context;
+use crate::{PruneReport, Result, TemplateDatabase};
+
+// Selects substitutes for `remove_subs_where`.
+pub enum SubPredicate<'a> {
+    // SQL LIKE pattern: `%` matches any run of characters, `_` a single one, and ASCII
+    // letters compare case-insensitively.
+    Like(String),
+    // Called with every substitute of the template as it is read.
+    Matches(Box<dyn FnMut(&str) -> bool + 'a>),
+}
+
+impl<'a> SubPredicate<'a> {
+    pub fn like(pattern: &str) -> SubPredicate<'a> {
+        SubPredicate::Like(pattern.to_string())
+    }
+
+    pub fn matches(predicate: impl FnMut(&str) -> bool + 'a) -> SubPredicate<'a> {
+        SubPredicate::Matches(Box::new(predicate))
+    }
+}
+
+impl fmt::Debug for SubPredicate<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubPredicate::Like(pattern) => f.debug_tuple("Like").field(pattern).finish(),
+            SubPredicate::Matches(_) => f.write_str("Matches(..)"),
+        }
+    }
+}
+
+impl TemplateDatabase {
+    fn execute_remove_subs_where(
+        db: &Connection,
+        template: &str,
+        mut predicate: SubPredicate<'_>,
+        dry_run: bool,
+    ) -> Result<PruneReport> {
+        let template_id: i64 = db.query_row(
+            "SELECT id FROM templates WHERE name = ?1",
+            [template],
+            |row| row.get(0),
+        )?;
+
+        let mut matched: Vec<(i64, String)> = Vec::new();
+        {
+            let mut stmt;
+            let mut rows = match &predicate {
+                SubPredicate::Like(pattern) => {
+                    stmt = db.prepare(
+                        "SELECT id, name FROM substitutes
+                         WHERE template_id = ?1 AND name LIKE ?2
+                         ORDER BY LOWER(name) ASC",
+                    )?;
+                    stmt.query((template_id, pattern))?
+                }
+                SubPredicate::Matches(_) => {
+                    stmt = db.prepare(
+                        "SELECT id, name FROM substitutes
+                         WHERE template_id = ?1
+                         ORDER BY LOWER(name) ASC",
+                    )?;
+                    stmt.query([template_id])?
+                }
+            };
+
+            while let Some(row) = rows.next()? {
+                let name: String = row.get(1)?;
+                let is_match = match &mut predicate {
+                    // Already filtered by the statement.
+                    SubPredicate::Like(_) => true,
+                    SubPredicate::Matches(matches) => matches(&name),
+                };
+                if is_match {
+                    matched.push((row.get(0)?, name));
+                }
+            }
+        }
+
+        if !dry_run {
+            let mut delete = db.prepare_cached("DELETE FROM substitutes WHERE id = ?1")?;
+            for (id, _) in &matched {
+                delete.execute([id])?;
+            }
+        }
+
+        let total: i64 = db.query_row(
+            "SELECT COUNT(*) FROM substitutes WHERE template_id = ?1",
+            [template_id],
+            |row| row.get(0),
+        )?;
+        let mut remaining = total as usize;
+        if dry_run {
+            remaining -= matched.len();
+        }
+
+        Ok(PruneReport {
+            pruned: matched.into_iter().map(|(_, name)| name).collect(),
+            remaining,
+        })
+    }
+
+    // Removes every substitute of `template` matching `predicate` in one transaction, e.g.
+    // `SubPredicate::matches(|x| x.contains(|c: char| c.is_ascii_digit()))`.
+    pub fn remove_subs_where(
+        &mut self,
+        template: &str,
+        predicate: SubPredicate<'_>,
+    ) -> Result<PruneReport> {
+        with_context("remove_subs_where", Some(template), None, || {
+            let tx = self.db.savepoint()?;
+            Self::execute_check_unlocked(&tx, template)?;
+            let report = Self::execute_remove_subs_where(&tx, template, predicate, false)?;
+            tx.commit()?;
+            Ok(report)
+        })
+    }
+
+    // Reports what `remove_subs_where` would remove without changing anything.
+    pub fn remove_subs_where_dry_run(
+        &self,
+        template: &str,
+        predicate: SubPredicate<'_>,
+    ) -> Result<PruneReport> {
+        let tx = self.read_transaction()?;
+        let report = Self::execute_remove_subs_where(&tx, template, predicate, true)?;
+        tx.commit()?;
+        Ok(report)
+    }
+}
@@ -0,0 +1,225 @@
+use rusqlite::types::Value;
+use rusqlite::{Params, Row};
+
+use crate::stats::word_count_sql;
+use crate::{Error, Result, TemplateDatabase};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Order {
+    #[default]
+    Alphabetical,
+    Recent,
+    Random,
+}
+
+// Composes substitute filters into a single statement, e.g.
+// `db.query().template("noun").tagged("animal").min_weight(2).order(Order::Recent).limit(50)
+// .fetch()`.
+#[derive(Debug, Clone)]
+pub struct Query<'db> {
+    db: &'db TemplateDatabase,
+    templates: Vec<String>,
+    contains: Option<String>,
+    tags: Vec<String>,
+    min_weight: Option<i64>,
+    word_count: Option<usize>,
+    enabled: Option<bool>,
+    order: Order,
+    limit: Option<usize>,
+    offset: usize,
+}
+
+impl TemplateDatabase {
+    pub fn query(&self) -> Query<'_> {
+        Query {
+            db: self,
+            templates: Vec::new(),
+            contains: None,
+            tags: Vec::new(),
+            min_weight: None,
+            word_count: None,
+            enabled: None,
+            order: Order::default(),
+            limit: None,
+            offset: 0,
+        }
+    }
+
+    // Runs caller supplied SQL inside a read transaction. Only a single statement that
+    // returns rows and cannot modify the database is accepted, anything else fails with
+    // `Error::NotReadOnly` before it is executed.
+    pub fn query_rows<T, P, F>(&self, sql: &str, params: P, mut mapper: F) -> Result<Vec<T>>
+    where
+        P: Params,
+        F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        let tx = self.read_transaction()?;
+        let rows = {
+            let mut stmt = tx.prepare(sql)?;
+            // Transaction control and ATTACH count as read-only but return no rows.
+            if !stmt.readonly() || stmt.column_count() == 0 {
+                return Err(Error::NotReadOnly(sql.to_string()));
+            }
+            let rows = stmt.query_map(params, |row| mapper(row))?;
+            rows.collect::<rusqlite::Result<Vec<T>>>()?
+        };
+        tx.commit()?;
+
+        Ok(rows)
+    }
+}
+
+pub(crate) fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl Query<'_> {
+    // Restricts results to the given template. Calling it again widens the query to
+    // substitutes of any of the named templates.
+    pub fn template(mut self, template: &str) -> Self {
+        self.templates.push(template.to_string());
+        self
+    }
+
+    pub fn contains(mut self, text: &str) -> Self {
+        self.contains = Some(text.to_string());
+        self
+    }
+
+    // Keeps only substitutes carrying `tag`. Calling it again narrows the query to
+    // substitutes carrying all of the named tags.
+    pub fn tagged(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    pub fn min_weight(mut self, weight: i64) -> Self {
+        self.min_weight = Some(weight);
+        self
+    }
+
+    // Keeps only substitutes of exactly `words` whitespace separated words.
+    pub fn word_count(mut self, words: usize) -> Self {
+        self.word_count = Some(words);
+        self
+    }
+
+    // Keeps only substitutes with a positive weight, or with `false` only the disabled ones.
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = Some(enabled);
+        self
+    }
+
+    pub fn order(mut self, order: Order) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    fn where_clause(&self, params: &mut Vec<Value>) -> String {
+        let mut conditions = Vec::new();
+
+        if !self.templates.is_empty() {
+            let placeholders = vec!["?"; self.templates.len()].join(", ");
+            conditions.push(format!("templates.name IN ({})", placeholders));
+            params.extend(self.templates.iter().cloned().map(Value::Text));
+        }
+
+        if let Some(text) = &self.contains {
+            conditions.push("substitutes.name LIKE ? ESCAPE '\\'".to_string());
+            params.push(Value::Text(format!("%{}%", escape_like(text))));
+        }
+
+        for tag in &self.tags {
+            conditions.push(
+                "EXISTS (SELECT 1 FROM substitute_tags
+                         WHERE substitute_id = substitutes.id AND tag = ?)"
+                    .to_string(),
+            );
+            params.push(Value::Text(tag.clone()));
+        }
+
+        if let Some(weight) = self.min_weight {
+            conditions.push("substitutes.weight >= ?".to_string());
+            params.push(Value::Integer(weight));
+        }
+
+        if let Some(words) = self.word_count {
+            conditions.push(format!("{} = ?", word_count_sql("substitutes.name")));
+            params.push(Value::Integer(words as i64));
+        }
+
+        match self.enabled {
+            Some(true) => conditions.push("substitutes.weight > 0".to_string()),
+            Some(false) => conditions.push("substitutes.weight <= 0".to_string()),
+            None => {}
+        }
+
+        if conditions.is_empty() {
+            return String::new();
+        }
+        format!("WHERE {}", conditions.join(" AND "))
+    }
+
+    pub fn fetch(&self) -> rusqlite::Result<Vec<String>> {
+        let mut params = Vec::new();
+        let where_clause = self.where_clause(&mut params);
+
+        let order = match self.order {
+            Order::Alphabetical => "LOWER(substitutes.name) ASC",
+            Order::Recent => "substitutes.id DESC",
+            Order::Random => "RANDOM()",
+        };
+
+        let sql = format!(
+            "SELECT substitutes.name
+             FROM substitutes
+             JOIN templates ON templates.id = substitutes.template_id
+             {}
+             ORDER BY {}
+             LIMIT ? OFFSET ?",
+            where_clause, order
+        );
+        params.push(Value::Integer(self.limit.map_or(-1, |x| x as i64)));
+        params.push(Value::Integer(self.offset as i64));
+
+        let mut stmt = self.db.db.prepare_cached(&sql)?;
+        let substitutes = stmt.query_map(rusqlite::params_from_iter(params), |row| row.get(0))?;
+
+        substitutes.collect()
+    }
+
+    // Number of matching substitutes, ignoring `limit` and `offset`.
+    pub fn count(&self) -> rusqlite::Result<usize> {
+        let mut params = Vec::new();
+        let where_clause = self.where_clause(&mut params);
+
+        let sql = format!(
+            "SELECT COUNT(*)
+             FROM substitutes
+             JOIN templates ON templates.id = substitutes.template_id
+             {}",
+            where_clause
+        );
+
+        let mut stmt = self.db.db.prepare_cached(&sql)?;
+        let count: i64 = stmt.query_row(rusqlite::params_from_iter(params), |row| row.get(0))?;
+        Ok(count as usize)
+    }
+}
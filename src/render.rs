@@ -0,0 +1,766 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{BufWriter, Write};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use rusqlite::Connection;
+
+use crate::rng::Rng;
+use crate::weights::WeightedSubs;
+use crate::{Error, Result, TemplateDatabase};
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Segment {
+    // Literal text as written, escapes included, so a rewritten pattern keeps them.
+    Text(String),
+    Placeholder(String),
+    // `[...]`, rendered with the given chance in percent, written `[30%...]`. The chance is
+    // kept along with its original spelling. Without a chance prefix
+    // `DEFAULT_OPTIONAL_PERCENT` applies.
+    Optional(Option<(u8, String)>, Vec<Segment>),
+    // `(a|b|...)`, one branch picked uniformly. Parentheses without a `|` are literal text,
+    // as is `\(a\|b\)`.
+    Alternation(Vec<Vec<Segment>>),
+}
+
+// Splits a pattern into literal text, `{template}` placeholders, `[optional]` segments and
+// `(a|b)` alternations. A bracket without a matching closing one is kept as literal text, and
+// a backslash before one of `ESCAPED` makes that character literal, so `\\` is a backslash.
+// Patterns stored before schema version 8 are escaped on upgrade to render as they did.
+pub(crate) fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    let mut rest = pattern;
+    parse_segments(&mut rest, &[]).map_or_else(Vec::new, |(segments, _)| segments)
+}
+
+// Parses up to and including the first of `close`, returning the segments and the closing
+// character, or to the end of the pattern when `close` is empty. Returns `None` when none
+// of `close` shows up.
+fn parse_segments(rest: &mut &str, close: &[char]) -> Option<(Vec<Segment>, Option<char>)> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+
+    while let Some(c) = rest.chars().next() {
+        *rest = &rest[c.len_utf8()..];
+        if close.contains(&c) {
+            push_text(&mut segments, &mut text);
+            return Some((segments, Some(c)));
+        }
+
+        match c {
+            '\\' if rest.starts_with(ESCAPED) => {
+                let escaped = rest.chars().next().unwrap_or_default();
+                text.push(c);
+                text.push(escaped);
+                *rest = &rest[escaped.len_utf8()..];
+            }
+            '{' => match rest.find(['{', '}']) {
+                Some(end) if rest.as_bytes()[end] == b'}' => {
+                    push_text(&mut segments, &mut text);
+                    segments.push(Segment::Placeholder(rest[..end].to_string()));
+                    *rest = &rest[end + 1..];
+                }
+                _ => text.push(c),
+            },
+            '[' => {
+                let mut inner = *rest;
+                let percent = parse_percent(&mut inner);
+                match parse_segments(&mut inner, &[']']) {
+                    Some((optional, _)) => {
+                        push_text(&mut segments, &mut text);
+                        segments.push(Segment::Optional(percent, optional));
+                        *rest = inner;
+                    }
+                    None => text.push(c),
+                }
+            }
+            '(' => {
+                let mut inner = *rest;
+                match parse_branches(&mut inner) {
+                    Some(branches) if branches.len() > 1 => {
+                        push_text(&mut segments, &mut text);
+                        segments.push(Segment::Alternation(branches));
+                        *rest = inner;
+                    }
+                    _ => text.push(c),
+                }
+            }
+            _ => text.push(c),
+        }
+    }
+
+    if !close.is_empty() {
+        return None;
+    }
+    push_text(&mut segments, &mut text);
+    Some((segments, None))
+}
+
+// Parses the `|` separated branches of an alternation up to and including its `)`.
+fn parse_branches(rest: &mut &str) -> Option<Vec<Vec<Segment>>> {
+    let mut branches = Vec::new();
+    loop {
+        let (branch, end) = parse_segments(rest, &['|', ')'])?;
+        branches.push(branch);
+        if end == Some(')') {
+            return Some(branches);
+        }
+    }
+}
+
+fn push_text(segments: &mut Vec<Segment>, text: &mut String) {
+    if !text.is_empty() {
+        segments.push(Segment::Text(std::mem::take(text)));
+    }
+}
+
+// Reads a `NN%` chance prefix of at most 100, returning it with its digits as written.
+fn parse_percent(rest: &mut &str) -> Option<(u8, String)> {
+    let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+    if !(1..=3).contains(&digits) || !rest[digits..].starts_with('%') {
+        return None;
+    }
+    let percent = rest[..digits].parse().ok().filter(|x| *x <= 100)?;
+    let written = rest[..digits].to_string();
+    *rest = &rest[digits + 1..];
+    Some((percent, written))
+}
+
+// Escapes every character with a meaning in patterns, so `text` renders as written.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if ESCAPED.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Drops the backslash from every escape in literal text.
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match chars.peek() {
+            Some(next) if c == '\\' && ESCAPED.contains(next) => unescaped.extend(chars.next()),
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+// Calls `f` with every placeholder, including those nested in optionals and alternations.
+pub(crate) fn visit_placeholders(segments: &mut [Segment], f: &mut impl FnMut(&mut String)) {
+    for segment in segments {
+        match segment {
+            Segment::Text(_) => {}
+            Segment::Placeholder(placeholder) => f(placeholder),
+            Segment::Optional(_, inner) => visit_placeholders(inner, f),
+            Segment::Alternation(branches) => {
+                for branch in branches {
+                    visit_placeholders(branch, f);
+                }
+            }
+        }
+    }
+}
+
+// Splits `noun@1` into the template name and the tag naming a sticky choice. Every
+// placeholder with the same template and tag renders the same value within one render.
+// A placeholder naming an existing template as a whole, like `user@host`, is not split.
+pub(crate) fn split_placeholder<'a>(
+    db: &Connection,
+    placeholder: &'a str,
+) -> rusqlite::Result<(&'a str, Option<&'a str>)> {
+    if let Some((template, tag)) = placeholder.rsplit_once('@') {
+        let mut stmt = db.prepare_cached("SELECT 1 FROM templates WHERE name = ?1")?;
+        if !stmt.exists([placeholder])? {
+            return Ok((template, Some(tag)));
+        }
+    }
+    Ok((placeholder, None))
+}
+
+pub(crate) fn write_pattern(segments: &[Segment]) -> String {
+    let mut pattern = String::new();
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => pattern.push_str(text),
+            Segment::Placeholder(template) => {
+                pattern.push('{');
+                pattern.push_str(template);
+                pattern.push('}');
+            }
+            Segment::Optional(percent, inner) => {
+                pattern.push('[');
+                if let Some((_, written)) = percent {
+                    pattern.push_str(written);
+                    pattern.push('%');
+                }
+                pattern.push_str(&write_pattern(inner));
+                pattern.push(']');
+            }
+            Segment::Alternation(branches) => {
+                let branches: Vec<String> = branches.iter().map(|x| write_pattern(x)).collect();
+                pattern.push('(');
+                pattern.push_str(&branches.join("|"));
+                pattern.push(')');
+            }
+        }
+    }
+    pattern
+}
+
+#[derive(Debug)]
+enum Piece {
+    Text(String),
+    Slot(usize),
+    // A slot whose choice is remembered under the given memo for the rest of the render.
+    Sticky(usize, usize),
+    Optional(u8, Vec<Piece>),
+    Alternation(Vec<Vec<Piece>>),
+}
+
+// A parsed pattern with the enabled substitutes of every referenced template loaded up front.
+#[derive(Debug, Default)]
+pub(crate) struct Grammar {
+    pieces: Vec<Piece>,
+    ids: Vec<i64>,
+    names: Vec<String>,
+    choices: Vec<WeightedSubs>,
+    // Key and slot of every sticky memo, in order of first appearance.
+    memos: Vec<(String, usize)>,
+}
+
+// Source of the random decisions made while rendering, so one walk over the pieces serves
+// plain, seeded and exhaustive rendering.
+trait Draw<'a> {
+    fn pick(&mut self, subs: &'a WeightedSubs, slot: usize) -> Option<&'a str>;
+    fn chance(&mut self, percent: u8) -> bool;
+    fn branch(&mut self, count: usize) -> usize;
+}
+
+impl<'a> Draw<'a> for Rng {
+    fn pick(&mut self, subs: &'a WeightedSubs, _: usize) -> Option<&'a str> {
+        subs.pick(self)
+    }
+
+    fn chance(&mut self, percent: u8) -> bool {
+        self.below(100) < percent as usize
+    }
+
+    fn branch(&mut self, count: usize) -> usize {
+        self.below(count)
+    }
+}
+
+// One stream per template plus one for optionals and alternations.
+struct Isolated {
+    streams: Vec<Rng>,
+    structure: Rng,
+}
+
+impl<'a> Draw<'a> for Isolated {
+    fn pick(&mut self, subs: &'a WeightedSubs, slot: usize) -> Option<&'a str> {
+        subs.pick(&mut self.streams[slot])
+    }
+
+    fn chance(&mut self, percent: u8) -> bool {
+        self.structure.chance(percent)
+    }
+
+    fn branch(&mut self, count: usize) -> usize {
+        self.structure.below(count)
+    }
+}
+
+// How often each value was picked, keyed by slot, for usage tracking.
+pub(crate) type Uses<'a> = HashMap<(usize, &'a str), u64>;
+
+// Passes decisions through from another draw, counting every value it picks.
+struct Counting<'d, 'a, D> {
+    draw: &'d mut D,
+    uses: &'d mut Uses<'a>,
+}
+
+impl<'a, D: Draw<'a>> Draw<'a> for Counting<'_, 'a, D> {
+    fn pick(&mut self, subs: &'a WeightedSubs, slot: usize) -> Option<&'a str> {
+        let sub = self.draw.pick(subs, slot)?;
+        *self.uses.entry((slot, sub)).or_default() += 1;
+        Some(sub)
+    }
+
+    fn chance(&mut self, percent: u8) -> bool {
+        self.draw.chance(percent)
+    }
+
+    fn branch(&mut self, count: usize) -> usize {
+        self.draw.branch(count)
+    }
+}
+
+// Reads every decision as the next digit of a mixed radix combination index.
+struct Combination(u128);
+
+impl<'a> Draw<'a> for Combination {
+    fn pick(&mut self, subs: &'a WeightedSubs, _: usize) -> Option<&'a str> {
+        if subs.is_empty() {
+            return None;
+        }
+        let count = subs.len() as u128;
+        let sub = &subs.subs[(self.0 % count) as usize];
+        self.0 /= count;
+        Some(sub)
+    }
+
+    fn chance(&mut self, percent: u8) -> bool {
+        match percent {
+            0 => false,
+            100 => true,
+            _ => {
+                let included = self.0 % 2 == 1;
+                self.0 /= 2;
+                included
+            }
+        }
+    }
+
+    fn branch(&mut self, count: usize) -> usize {
+        let branch = (self.0 % count as u128) as usize;
+        self.0 /= count as u128;
+        branch
+    }
+}
+
+impl Grammar {
+    // Pinned templates, keyed by lowercase name, render their pinned value.
+    pub(crate) fn load(
+        tx: &Connection,
+        pattern: &str,
+        pins: &HashMap<String, String>,
+    ) -> rusqlite::Result<Grammar> {
+        Grammar::load_with(tx, pattern, &HashMap::new(), pins)
+    }
+
+    // Like `load`, but placeholders named in `vars` (ignoring case) become literal text
+    // instead of being looked up in the database. `vars` win over pins.
+    pub(crate) fn load_with(
+        tx: &Connection,
+        pattern: &str,
+        vars: &HashMap<&str, &str>,
+        pins: &HashMap<String, String>,
+    ) -> rusqlite::Result<Grammar> {
+        let mut grammar = Grammar::default();
+        grammar.pieces = grammar.load_segments(tx, vars, pins, parse_pattern(pattern))?;
+        Ok(grammar)
+    }
+
+    fn load_segments(
+        &mut self,
+        tx: &Connection,
+        vars: &HashMap<&str, &str>,
+        pins: &HashMap<String, String>,
+        segments: Vec<Segment>,
+    ) -> rusqlite::Result<Vec<Piece>> {
+        let mut find_id = tx.prepare_cached("SELECT id FROM templates WHERE name = ?1")?;
+        let mut pieces = Vec::new();
+
+        for segment in segments {
+            match segment {
+                Segment::Text(text) => pieces.push(Piece::Text(unescape(&text))),
+                Segment::Optional(percent, inner) => {
+                    let inner = self.load_segments(tx, vars, pins, inner)?;
+                    let percent = percent.map_or(DEFAULT_OPTIONAL_PERCENT, |(x, _)| x);
+                    pieces.push(Piece::Optional(percent, inner));
+                }
+                Segment::Alternation(branches) => {
+                    let branches = branches
+                        .into_iter()
+                        .map(|branch| self.load_segments(tx, vars, pins, branch))
+                        .collect::<rusqlite::Result<_>>()?;
+                    pieces.push(Piece::Alternation(branches));
+                }
+                Segment::Placeholder(placeholder) => {
+                    let (template, tag) = split_placeholder(tx, &placeholder)?;
+                    let bound = vars
+                        .iter()
+                        .find(|(name, _)| name.eq_ignore_ascii_case(template))
+                        .map(|(_, value)| *value)
+                        .or_else(|| pins.get(&template.to_ascii_lowercase()).map(|x| x.as_str()));
+                    if let Some(value) = bound {
+                        pieces.push(Piece::Text(value.to_string()));
+                        continue;
+                    }
+
+                    let template_id: i64 = find_id.query_row([template], |row| row.get(0))?;
+                    let slot = match self.ids.iter().position(|id| *id == template_id) {
+                        Some(slot) => slot,
+                        None => {
+                            self.ids.push(template_id);
+                            self.names.push(template.to_string());
+                            self.choices.push(WeightedSubs::load(tx, template_id)?);
+                            self.choices.len() - 1
+                        }
+                    };
+
+                    let Some(tag) = tag else {
+                        pieces.push(Piece::Slot(slot));
+                        continue;
+                    };
+                    let key = format!("{}@{}", slot, tag.to_ascii_lowercase());
+                    let memo = match self.memos.iter().position(|(x, _)| *x == key) {
+                        Some(memo) => memo,
+                        None => {
+                            self.memos.push((key, slot));
+                            self.memos.len() - 1
+                        }
+                    };
+                    pieces.push(Piece::Sticky(slot, memo));
+                }
+            }
+        }
+
+        Ok(pieces)
+    }
+
+    // Number of distinct decision sequences, an upper bound on the distinct outputs.
+    pub(crate) fn combinations(&self) -> u128 {
+        self.memos
+            .iter()
+            .map(|(_, slot)| self.choices[*slot].len().max(1) as u128)
+            .fold(self.count(&self.pieces), |total, count| {
+                total.saturating_mul(count)
+            })
+    }
+
+    fn count(&self, pieces: &[Piece]) -> u128 {
+        pieces.iter().fold(1u128, |total, piece| {
+            let count = match piece {
+                Piece::Text(_) | Piece::Sticky(..) => 1,
+                Piece::Slot(slot) => self.choices[*slot].len().max(1) as u128,
+                Piece::Optional(0 | 100, inner) => self.count(inner),
+                Piece::Optional(_, inner) => self.count(inner).saturating_mul(2),
+                Piece::Alternation(branches) => branches
+                    .iter()
+                    .map(|branch| self.count(branch))
+                    .max()
+                    .unwrap_or(1)
+                    .saturating_mul(branches.len() as u128),
+            };
+            total.saturating_mul(count)
+        })
+    }
+
+    // Renders the `index`th combination, counting decisions as mixed radix digits.
+    fn render_combination<'a>(
+        &'a self,
+        index: u128,
+        uses: Option<&mut Uses<'a>>,
+        out: &mut String,
+    ) {
+        self.render_by(&mut Combination(index), uses, out);
+    }
+
+    // Picks are added to `uses` when given.
+    pub(crate) fn render_into<'a>(
+        &'a self,
+        rng: &mut Rng,
+        uses: Option<&mut Uses<'a>>,
+        out: &mut String,
+    ) {
+        self.render_by(rng, uses, out);
+    }
+
+    // Like `render_into`, but every template draws from its own stream derived from `seed`,
+    // so adding or removing other placeholders leaves its picks unchanged.
+    fn render_isolated<'a>(&'a self, seed: &Rng, uses: Option<&mut Uses<'a>>, out: &mut String) {
+        let mut draw = Isolated {
+            streams: self.names.iter().map(|x| seed.derive(x)).collect(),
+            structure: seed.derive("[]"),
+        };
+        self.render_by(&mut draw, uses, out);
+    }
+
+    fn render_by<'a>(
+        &'a self,
+        draw: &mut impl Draw<'a>,
+        uses: Option<&mut Uses<'a>>,
+        out: &mut String,
+    ) {
+        let mut memory = vec![None; self.memos.len()];
+        match uses {
+            Some(uses) => {
+                let mut draw = Counting { draw, uses };
+                self.render_pieces(&self.pieces, &mut draw, &mut memory, out);
+            }
+            None => self.render_pieces(&self.pieces, draw, &mut memory, out),
+        }
+    }
+
+    // Sticky slots only draw on their memo's first appearance and repeat that choice after.
+    fn render_pieces<'a>(
+        &'a self,
+        pieces: &'a [Piece],
+        draw: &mut impl Draw<'a>,
+        memory: &mut [Option<Option<&'a str>>],
+        out: &mut String,
+    ) {
+        for piece in pieces {
+            let sub = match piece {
+                Piece::Text(text) => Some(text.as_str()),
+                Piece::Slot(slot) => draw.pick(&self.choices[*slot], *slot),
+                Piece::Sticky(slot, memo) => {
+                    *memory[*memo].get_or_insert_with(|| draw.pick(&self.choices[*slot], *slot))
+                }
+                Piece::Optional(percent, inner) => {
+                    if draw.chance(*percent) {
+                        self.render_pieces(inner, draw, memory, out);
+                    }
+                    None
+                }
+                Piece::Alternation(branches) => {
+                    let branch = &branches[draw.branch(branches.len())];
+                    self.render_pieces(branch, draw, memory, out);
+                    None
+                }
+            };
+            if let Some(sub) = sub {
+                out.push_str(sub);
+            }
+        }
+    }
+}
+
+impl TemplateDatabase {
+    // Where usage tracking is on, renders collect their picks here instead of writing each.
+    fn new_uses<'a>(&self) -> Option<Uses<'a>> {
+        self.track_usage.then(HashMap::new)
+    }
+
+    fn record_uses(&self, grammar: &Grammar, uses: Option<Uses>) -> rusqlite::Result<()> {
+        let Some(uses) = uses.filter(|x| !x.is_empty()) else {
+            return Ok(());
+        };
+        let tx = self.read_transaction()?;
+        for ((slot, sub), count) in uses {
+            Self::execute_record_use(&tx, grammar.ids[slot], sub, count)?;
+        }
+        tx.commit()
+    }
+
+    pub fn render(&self, pattern: &str) -> rusqlite::Result<String> {
+        let tx = self.read_transaction()?;
+        let grammar = Grammar::load(&tx, pattern, &self.pins)?;
+        let mut rng = Rng::from_db(&tx)?;
+        tx.commit()?;
+
+        let mut uses = self.new_uses();
+        let mut line = String::new();
+        grammar.render_into(&mut rng, uses.as_mut(), &mut line);
+        self.record_uses(&grammar, uses)?;
+        Ok(line)
+    }
+
+    // Renders `pattern`, resolving placeholders from `vars` first and from stored templates
+    // otherwise, so values known only to the caller can be mixed with word lists.
+    pub fn render_with(
+        &self,
+        pattern: &str,
+        vars: &HashMap<&str, &str>,
+    ) -> rusqlite::Result<String> {
+        let tx = self.read_transaction()?;
+        let grammar = Grammar::load_with(&tx, pattern, vars, &self.pins)?;
+        let mut rng = Rng::from_db(&tx)?;
+        tx.commit()?;
+
+        let mut uses = self.new_uses();
+        let mut line = String::new();
+        grammar.render_into(&mut rng, uses.as_mut(), &mut line);
+        self.record_uses(&grammar, uses)?;
+        Ok(line)
+    }
+
+    // Deterministic render for a given seed and database state. Each template gets its own
+    // random stream derived from `seed` and the template name, so a new placeholder in the
+    // pattern does not change what the existing ones pick. Derive `seed` per session with
+    // `Rng::derive` to keep sessions independent as well.
+    pub fn render_seeded(&self, pattern: &str, seed: &Rng) -> rusqlite::Result<String> {
+        let tx = self.read_transaction()?;
+        let grammar = Grammar::load(&tx, pattern, &self.pins)?;
+        tx.commit()?;
+
+        let mut uses = self.new_uses();
+        let mut line = String::new();
+        grammar.render_isolated(seed, uses.as_mut(), &mut line);
+        self.record_uses(&grammar, uses)?;
+        Ok(line)
+    }
+
+    // Writes `n` renders of `pattern` to `writer`, one per line. Every referenced template
+    // is read once inside a single read transaction, so the output is consistent even if
+    // another connection writes meanwhile.
+    pub fn generate_corpus<W: Write>(&self, pattern: &str, n: usize, writer: W) -> Result<()> {
+        let tx = self.read_transaction()?;
+        let grammar = Grammar::load(&tx, pattern, &self.pins)?;
+        let mut rng = Rng::from_db(&tx)?;
+        tx.commit()?;
+
+        let mut writer = BufWriter::new(writer);
+        let mut uses = self.new_uses();
+        let mut line = String::new();
+
+        for _ in 0..n {
+            line.clear();
+            grammar.render_into(&mut rng, uses.as_mut(), &mut line);
+            line.push('\n');
+            writer.write_all(line.as_bytes())?;
+        }
+
+        writer.flush()?;
+        self.record_uses(&grammar, uses)?;
+
+        Ok(())
+    }
+
+    // Returns `n` distinct renders of `pattern`. Small grammars are enumerated outright,
+    // larger ones are sampled with a bounded number of retries, failing with
+    // `Error::NotEnoughUniqueOutputs` when the pattern cannot produce enough variety.
+    pub fn generate_unique(&self, pattern: &str, n: usize) -> Result<Vec<String>> {
+        let tx = self.read_transaction()?;
+        let grammar = Grammar::load(&tx, pattern, &self.pins)?;
+        let mut rng = Rng::from_db(&tx)?;
+        tx.commit()?;
+
+        let combinations = grammar.combinations();
+        let mut seen = HashSet::new();
+        // Every output with the picks that made it, so only those returned count as used.
+        let mut outputs = Vec::new();
+
+        if combinations <= (n as u128).saturating_mul(ENUMERATION_FACTOR) {
+            for index in 0..combinations {
+                let mut uses = self.new_uses();
+                let mut line = String::new();
+                grammar.render_combination(index, uses.as_mut(), &mut line);
+                if seen.insert(line.clone()) {
+                    outputs.push((line, uses));
+                }
+            }
+
+            if outputs.len() < n {
+                return Err(Error::NotEnoughUniqueOutputs {
+                    requested: n,
+                    found: outputs.len(),
+                });
+            }
+
+            for i in (1..outputs.len()).rev() {
+                outputs.swap(i, rng.below(i + 1));
+            }
+            outputs.truncate(n);
+        } else {
+            let max_attempts = n.saturating_mul(MAX_ATTEMPTS_PER_OUTPUT);
+            let mut attempts = 0;
+
+            while outputs.len() < n {
+                if attempts == max_attempts {
+                    return Err(Error::NotEnoughUniqueOutputs {
+                        requested: n,
+                        found: outputs.len(),
+                    });
+                }
+                attempts += 1;
+
+                let mut uses = self.new_uses();
+                let mut line = String::new();
+                grammar.render_into(&mut rng, uses.as_mut(), &mut line);
+                if seen.insert(line.clone()) {
+                    outputs.push((line, uses));
+                }
+            }
+        }
+
+        let (outputs, picks): (Vec<String>, Vec<_>) = outputs.into_iter().unzip();
+        let mut uses = self.new_uses();
+        if let Some(uses) = &mut uses {
+            for (key, count) in picks.into_iter().flatten().flatten() {
+                *uses.entry(key).or_default() += count;
+            }
+        }
+        self.record_uses(&grammar, uses)?;
+
+        Ok(outputs)
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl TemplateDatabase {
+    // Like `generate_corpus`, but rendering is spread across rayon's thread pool. Each job
+    // draws from its own random stream, so the lines differ from `generate_corpus`; only the
+    // line count and the distribution they are drawn from are the same. Templates are loaded
+    // once into a shared snapshot, jobs render their share of each chunk into separate
+    // buffers and the buffers are written out in order. A panic in a job is passed on as is.
+    pub fn generate_corpus_parallel<W: Write>(
+        &self,
+        pattern: &str,
+        n: usize,
+        writer: W,
+    ) -> Result<()> {
+        let tx = self.read_transaction()?;
+        let grammar = Grammar::load(&tx, pattern, &self.pins)?;
+        let mut rng = Rng::from_db(&tx)?;
+        tx.commit()?;
+
+        let threads = rayon::current_num_threads();
+        let track_usage = self.track_usage;
+        let mut writer = BufWriter::new(writer);
+        let mut uses = self.new_uses();
+        let mut remaining = n;
+
+        while remaining > 0 {
+            let chunk = remaining.min(PARALLEL_CHUNK_LINES * threads);
+            let per_job = chunk.div_ceil(threads);
+            let jobs: Vec<(usize, Rng)> = (0..chunk)
+                .step_by(per_job)
+                .map(|start| (per_job.min(chunk - start), Rng::new(rng.next_u64())))
+                .collect();
+
+            let buffers: Vec<_> = jobs
+                .into_par_iter()
+                .map(|(lines, mut rng)| {
+                    let mut uses = track_usage.then(HashMap::new);
+                    let mut buffer = String::new();
+                    for _ in 0..lines {
+                        grammar.render_into(&mut rng, uses.as_mut(), &mut buffer);
+                        buffer.push('\n');
+                    }
+                    (buffer, uses)
+                })
+                .collect();
+
+            for (buffer, job_uses) in buffers {
+                writer.write_all(buffer.as_bytes())?;
+                if let Some(uses) = &mut uses {
+                    for (key, count) in job_uses.into_iter().flatten() {
+                        *uses.entry(key).or_default() += count;
+                    }
+                }
+            }
+
+            remaining -= chunk;
+        }
+
+        writer.flush()?;
+        self.record_uses(&grammar, uses)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "parallel")]
+const PARALLEL_CHUNK_LINES: usize = 10_000;
+const DEFAULT_OPTIONAL_PERCENT: u8 = 50;
+// Characters a backslash makes literal in a pattern.
+const ESCAPED: &[char] = &['\\', '[', ']', '(', ')', '|'];
+const ENUMERATION_FACTOR: u128 = 4;
+const MAX_ATTEMPTS_PER_OUTPUT: usize = 16;
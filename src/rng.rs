@@ -0,0 +1,82 @@
+#[cfg(feature = "std")]
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "std")]
+use std::hash::{BuildHasher, Hasher};
+
+#[cfg(feature = "sqlite")]
+use rusqlite::Connection;
+
+// SplitMix64, small and good enough for picking substitutes.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    // Seeds from the per-process random keys std uses for `HashMap`.
+    #[cfg(feature = "std")]
+    pub fn from_entropy() -> Rng {
+        Rng::new(RandomState::new().build_hasher().finish())
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn from_db(db: &Connection) -> rusqlite::Result<Rng> {
+        let seed: i64 = db.query_row("SELECT random()", [], |row| row.get(0))?;
+        Ok(Rng::new(seed as u64))
+    }
+
+    // Independent stream for `key`, e.g. a template or session name. Deriving does not
+    // advance this generator, so each stream only depends on the seed and its key, and
+    // drawing from one stream never shifts another. Keys compare ASCII case-insensitively.
+    pub fn derive(&self, key: &str) -> Rng {
+        // FNV-1a, then one SplitMix64 step to spread the bits.
+        let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+        for byte in key.bytes() {
+            hash ^= byte.to_ascii_lowercase() as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+        }
+        let mut stream = Rng::new(self.state ^ hash);
+        Rng::new(stream.next_u64())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Returns a value in 0..n, n must be non-zero.
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        ((self.next_u64() as u128 * n as u128) >> 64) as usize
+    }
+
+    // Picks an index with probability proportional to its weight. Weights of zero or less
+    // are never picked, so this returns `None` when no weight is positive. The total is
+    // summed in 128 bits, so any number of `i64::MAX` weights is fine.
+    pub(crate) fn weighted_index(&mut self, weights: &[i64]) -> Option<usize> {
+        let total: u128 = weights.iter().map(|x| (*x).max(0) as u128).sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut target = if total <= u64::MAX as u128 {
+            (self.next_u64() as u128 * total) >> 64
+        } else {
+            (((self.next_u64() as u128) << 64) | self.next_u64() as u128) % total
+        };
+        for (index, weight) in weights.iter().enumerate() {
+            let weight = (*weight).max(0) as u128;
+            if target < weight {
+                return Some(index);
+            }
+            target -= weight;
+        }
+        None
+    }
+}
@@ -0,0 +1,90 @@
+use std::ops::Deref;
+
+use rusqlite::{Connection, Transaction};
+
+use crate::{Result, TemplateDatabase};
+
+// A read transaction of its own, or the one already open when called from `read_snapshot`.
+pub(crate) enum ReadTransaction<'db> {
+    Owned(Transaction<'db>),
+    Nested(&'db Connection),
+}
+
+impl ReadTransaction<'_> {
+    pub(crate) fn commit(self) -> rusqlite::Result<()> {
+        match self {
+            ReadTransaction::Owned(tx) => tx.commit(),
+            ReadTransaction::Nested(_) => Ok(()),
+        }
+    }
+}
+
+impl Deref for ReadTransaction<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match self {
+            ReadTransaction::Owned(tx) => tx,
+            ReadTransaction::Nested(db) => db,
+        }
+    }
+}
+
+impl TemplateDatabase {
+    pub(crate) fn read_transaction(&self) -> rusqlite::Result<ReadTransaction<'_>> {
+        if self.db.is_autocommit() {
+            Ok(ReadTransaction::Owned(self.db.unchecked_transaction()?))
+        } else {
+            Ok(ReadTransaction::Nested(&self.db))
+        }
+    }
+
+    // Runs several reads against one consistent state of the database, e.g. `get_templates`
+    // followed by `get_subs` for each of them, while other connections keep writing. The
+    // view is read-only, writes through it fail and usage is not recorded. Inside a batch the
+    // batch's transaction is reused, so its uncommitted writes are visible.
+    pub fn read_snapshot<T, F>(&mut self, read: F) -> Result<T>
+    where
+        F: FnOnce(&TemplateDatabase) -> Result<T>,
+    {
+        self.db.execute_batch("PRAGMA query_only = ON;")?;
+        let track_usage = std::mem::replace(&mut self.track_usage, false);
+        let mut snapshot = Snapshot {
+            db: self,
+            track_usage,
+            in_transaction: false,
+        };
+
+        if !snapshot.db.is_batching() {
+            snapshot.db.db.execute_batch("BEGIN DEFERRED;")?;
+            snapshot.in_transaction = true;
+        }
+
+        let result = read(snapshot.db);
+
+        if snapshot.in_transaction {
+            snapshot.db.db.execute_batch("COMMIT;")?;
+            snapshot.in_transaction = false;
+        }
+
+        result
+    }
+}
+
+// Puts the connection back the way `read_snapshot` found it when dropped, so a panicking
+// closure does not leave it read-only or in an open transaction.
+struct Snapshot<'db> {
+    db: &'db mut TemplateDatabase,
+    track_usage: bool,
+    in_transaction: bool,
+}
+
+impl Drop for Snapshot<'_> {
+    fn drop(&mut self) {
+        if self.in_transaction {
+            let _ = self.db.db.execute_batch("ROLLBACK;");
+        }
+        self.db.track_usage = self.track_usage;
+        let _ = self.db.db.execute_batch("PRAGMA query_only = OFF;");
+    }
+}
@@ -0,0 +1,104 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::Infallible;
+
+use crate::{Rng, StaticTemplates};
+
+// Read-only lookup and random selection, implemented by both the SQLite database and the
+// static embedded format so the same code can run with or without std. Missing templates
+// are `None`, templates with nothing selectable select `""`.
+pub trait TemplateSource {
+    type Error;
+
+    fn templates(&self) -> Result<Vec<String>, Self::Error>;
+
+    fn subs(&self, template: &str) -> Result<Option<Vec<String>>, Self::Error>;
+
+    fn random_sub(&self, template: &str, rng: &mut Rng) -> Result<Option<String>, Self::Error>;
+
+    // The substitutes `random_sub` chooses from with their weights, leaving out disabled
+    // ones. Sources without weights give every substitute a weight of 1.
+    fn weighted_subs(&self, template: &str) -> Result<Option<Vec<(String, i64)>>, Self::Error> {
+        Ok(self
+            .subs(template)?
+            .map(|subs| subs.into_iter().map(|x| (x, 1)).collect()))
+    }
+
+    // Counts `substitute` as picked from `template` by someone choosing from a copy of
+    // `weighted_subs`, for sources that track usage. Does nothing by default.
+    fn record_pick(&self, _template: &str, _substitute: &str) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl TemplateSource for StaticTemplates {
+    type Error = Infallible;
+
+    fn templates(&self) -> Result<Vec<String>, Infallible> {
+        Ok(self
+            .get_templates()
+            .into_iter()
+            .map(|x| x.to_string())
+            .collect())
+    }
+
+    fn subs(&self, template: &str) -> Result<Option<Vec<String>>, Infallible> {
+        Ok(self
+            .get_subs(template)
+            .map(|subs| subs.iter().map(|x| x.to_string()).collect()))
+    }
+
+    fn random_sub(&self, template: &str, rng: &mut Rng) -> Result<Option<String>, Infallible> {
+        Ok(self.get_random_sub(template, rng).map(|x| x.to_string()))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl TemplateSource for crate::TemplateDatabase {
+    type Error = crate::Error;
+
+    fn templates(&self) -> crate::Result<Vec<String>> {
+        Ok(self.get_templates()?)
+    }
+
+    fn subs(&self, template: &str) -> crate::Result<Option<Vec<String>>> {
+        match self.find_template(template)? {
+            Some(_) => Ok(self.get_subs(template).map(Some)?),
+            None => Ok(None),
+        }
+    }
+
+    fn random_sub(&self, template: &str, rng: &mut Rng) -> crate::Result<Option<String>> {
+        if let Some(pinned) = self.pinned(template) {
+            return Ok(Some(pinned.to_string()));
+        }
+        let Some((template_id, _)) = self.find_template(template)? else {
+            return Ok(None);
+        };
+
+        let sub = self.pick_weighted(template_id, rng)?;
+        Ok(Some(sub))
+    }
+
+    fn weighted_subs(&self, template: &str) -> crate::Result<Option<Vec<(String, i64)>>> {
+        if let Some(pinned) = self.pinned(template) {
+            return Ok(Some(alloc::vec![(pinned.to_string(), 1)]));
+        }
+        let Some((template_id, _)) = self.find_template(template)? else {
+            return Ok(None);
+        };
+
+        let subs = crate::WeightedSubs::load(&self.db, template_id)?;
+        Ok(Some(subs.subs.into_iter().zip(subs.weights).collect()))
+    }
+
+    fn record_pick(&self, template: &str, substitute: &str) -> crate::Result<()> {
+        if !self.track_usage || self.pinned(template).is_some() {
+            return Ok(());
+        }
+        if let Some((template_id, _)) = self.find_template(template)? {
+            Self::execute_record_use(&self.db, template_id, substitute, 1)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use rusqlite::functions::FunctionFlags;
+use rusqlite::Connection;
+
+use crate::rng::Rng;
+use crate::weights::WeightedSubs;
+use crate::TemplateDatabase;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LengthStats {
+    pub count: usize,
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+}
+
+// SQL expression for the number of whitespace separated words in `column`. Every query that
+// filters or groups by word count uses it, so they all agree on what a word is.
+pub(crate) fn word_count_sql(column: &str) -> String {
+    format!("word_count({})", column)
+}
+
+// Counts words in Rust, so runs of spaces, tabs and line breaks all separate words once.
+pub(crate) fn create_word_count_function(db: &Connection) -> rusqlite::Result<()> {
+    db.create_scalar_function(
+        "word_count",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let text = ctx.get_raw(0).as_str_or_null()?.unwrap_or_default();
+            Ok(text.split_whitespace().count() as i64)
+        },
+    )
+}
+
+impl TemplateDatabase {
+    fn histogram(&self, template: &str, value: &str) -> rusqlite::Result<Vec<(usize, usize)>> {
+        let template_id = self.find_template_id(template)?;
+        let mut stmt = self.db.prepare_cached(&format!(
+            "SELECT {0} AS value, COUNT(*)
+             FROM substitutes
+             WHERE template_id = ?1
+             GROUP BY value
+             ORDER BY value ASC;",
+            value
+        ))?;
+
+        let buckets = stmt.query_map([template_id], |row| {
+            Ok((
+                row.get::<_, i64>(0)? as usize,
+                row.get::<_, i64>(1)? as usize,
+            ))
+        })?;
+
+        buckets.collect()
+    }
+
+    // Pairs of substitute length in characters and how many substitutes have it, shortest
+    // first. Lengths without any substitutes are left out.
+    pub fn sub_length_histogram(&self, template: &str) -> rusqlite::Result<Vec<(usize, usize)>> {
+        self.histogram(template, "LENGTH(name)")
+    }
+
+    // Same as `sub_length_histogram`, but counting whitespace separated words.
+    pub fn sub_word_count_histogram(
+        &self,
+        template: &str,
+    ) -> rusqlite::Result<Vec<(usize, usize)>> {
+        self.histogram(template, &word_count_sql("name"))
+    }
+
+    // Substitutes of exactly `words` whitespace separated words, e.g. 1 to leave out entries
+    // like "United States" where a single word is needed.
+    pub fn get_subs_by_word_count(
+        &self,
+        template: &str,
+        words: usize,
+    ) -> rusqlite::Result<Vec<String>> {
+        self.find_template_id(template)?;
+        self.query().template(template).word_count(words).fetch()
+    }
+
+    // Like `get_random_subs`, but only picks among substitutes of exactly `words` words.
+    // Returns an empty string when there are none.
+    pub fn get_random_subs_by_word_count(
+        &self,
+        template: &str,
+        words: usize,
+    ) -> rusqlite::Result<String> {
+        if let Some(pinned) = self.pinned(template) {
+            return Ok(pinned.to_string());
+        }
+        let template_id = self.find_template_id(template)?;
+        let subs = WeightedSubs::load_with_word_count(&self.db, &template_id, words)?;
+        let mut rng = Rng::from_db(&self.db)?;
+        self.pick_from(subs, template_id, &mut rng)
+    }
+
+    pub fn sub_length_stats(&self, template: &str) -> rusqlite::Result<LengthStats> {
+        let template_id = self.find_template_id(template)?;
+        self.db.query_row(
+            "SELECT COUNT(*), MIN(LENGTH(name)), MAX(LENGTH(name)), AVG(LENGTH(name))
+             FROM substitutes
+             WHERE template_id = ?1",
+            [template_id],
+            |row| {
+                Ok(LengthStats {
+                    count: row.get::<_, i64>(0)? as usize,
+                    min: row.get::<_, Option<i64>>(1)?.unwrap_or(0) as usize,
+                    max: row.get::<_, Option<i64>>(2)?.unwrap_or(0) as usize,
+                    mean: row.get::<_, Option<f64>>(3)?.unwrap_or(0.0),
+                })
+            },
+        )
+    }
+}
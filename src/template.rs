@@ -0,0 +1,173 @@
+use rusqlite::OptionalExtension;
+
+use crate::error::with_context;
+use crate::{Result, Rng, TemplateDatabase, UpdatedValues};
+
+// Handle to a single template, resolved once by name and then addressed by id, so it stays
+// valid across renames.
+#[derive(Debug)]
+pub struct Template<'db> {
+    db: &'db mut TemplateDatabase,
+    id: i64,
+    name: String,
+}
+
+// A template name that may or may not exist yet, see `TemplateDatabase::template_entry`.
+#[derive(Debug)]
+pub struct TemplateEntry<'db, 'a> {
+    db: &'db mut TemplateDatabase,
+    name: &'a str,
+}
+
+impl TemplateDatabase {
+    pub(crate) fn find_template(&self, name: &str) -> rusqlite::Result<Option<(i64, String)>> {
+        self.db
+            .query_row(
+                "SELECT id, name FROM templates WHERE name = ?1",
+                [name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+    }
+
+    pub fn template(&mut self, name: &str) -> rusqlite::Result<Template<'_>> {
+        let (id, name) = self
+            .find_template(name)?
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+        Ok(Template { db: self, id, name })
+    }
+
+    pub fn template_entry<'a>(&mut self, name: &'a str) -> TemplateEntry<'_, 'a> {
+        TemplateEntry { db: self, name }
+    }
+}
+
+impl<'db> TemplateEntry<'db, '_> {
+    pub fn get(self) -> rusqlite::Result<Option<Template<'db>>> {
+        Ok(self
+            .db
+            .find_template(self.name)?
+            .map(|(id, name)| Template {
+                db: self.db,
+                id,
+                name,
+            }))
+    }
+
+    pub fn or_create(self) -> rusqlite::Result<Template<'db>> {
+        self.db.db.execute(
+            "INSERT OR IGNORE INTO templates (name) VALUES (?1)",
+            [self.name],
+        )?;
+        self.db.template(self.name)
+    }
+}
+
+impl Template<'_> {
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn add(&mut self, substitute: &str) -> Result<bool> {
+        with_context("insert_sub", Some(&self.name), Some(substitute), || {
+            let tx = self.db.db.savepoint()?;
+            TemplateDatabase::execute_check_unlocked_id(&tx, self.id)?;
+            let result = tx.execute(
+                "INSERT OR IGNORE INTO substitutes (name, template_id) VALUES (?1, ?2)",
+                (substitute, self.id),
+            )?;
+            tx.commit()?;
+            Ok(result > 0)
+        })
+    }
+
+    pub fn add_all<'a>(&mut self, substitutes: &[&'a str]) -> Result<UpdatedValues<'a>> {
+        with_context("insert_subs", Some(&self.name), None, || {
+            let tx = self.db.db.savepoint()?;
+            TemplateDatabase::execute_check_unlocked_id(&tx, self.id)?;
+            let mut inserted_subs = UpdatedValues::new();
+
+            for sub in substitutes {
+                let result = tx.execute(
+                    "INSERT OR IGNORE INTO substitutes (name, template_id) VALUES (?1, ?2)",
+                    (*sub, self.id),
+                )?;
+                if result > 0 {
+                    inserted_subs.push(*sub);
+                }
+            }
+
+            tx.commit()?;
+
+            Ok(inserted_subs)
+        })
+    }
+
+    pub fn remove(&mut self, substitute: &str) -> Result<bool> {
+        with_context("remove_sub", Some(&self.name), Some(substitute), || {
+            let tx = self.db.db.savepoint()?;
+            TemplateDatabase::execute_check_unlocked_id(&tx, self.id)?;
+            let result = tx.execute(
+                "DELETE FROM substitutes WHERE template_id = ?1 AND name = ?2",
+                (self.id, substitute),
+            )?;
+            tx.commit()?;
+            Ok(result > 0)
+        })
+    }
+
+    pub fn subs(&self) -> rusqlite::Result<Vec<String>> {
+        let mut stmt = self.db.db.prepare_cached(
+            "SELECT substitutes.name
+             FROM substitutes
+             WHERE template_id = ?1
+             ORDER BY LOWER(substitutes.name) ASC;",
+        )?;
+
+        let substitutes = stmt.query_map([self.id], |row| row.get(0))?;
+
+        substitutes.collect()
+    }
+
+    pub fn random(&self) -> rusqlite::Result<String> {
+        if let Some(pinned) = self.db.pinned(&self.name) {
+            return Ok(pinned.to_string());
+        }
+        let mut rng = Rng::from_db(&self.db.db)?;
+        self.db.pick_weighted(self.id, &mut rng)
+    }
+
+    pub fn rename(&mut self, new_name: &str) -> Result<bool> {
+        let renamed = with_context("rename_template", Some(&self.name), Some(new_name), || {
+            let tx = self.db.db.savepoint()?;
+            TemplateDatabase::execute_check_unlocked_id(&tx, self.id)?;
+            let result = tx.execute(
+                "UPDATE templates SET name = ?1 WHERE id = ?2",
+                (new_name, self.id),
+            )?;
+            tx.commit()?;
+            Ok(result > 0)
+        })?;
+        if renamed {
+            self.name = new_name.to_string();
+        }
+        Ok(renamed)
+    }
+
+    pub fn len(&self) -> rusqlite::Result<usize> {
+        let count: i64 = self.db.db.query_row(
+            "SELECT COUNT(*) FROM substitutes WHERE template_id = ?1",
+            [self.id],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    pub fn is_empty(&self) -> rusqlite::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
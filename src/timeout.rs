@@ -0,0 +1,34 @@
+use std::time::{Duration, Instant};
+
+use rusqlite::ErrorCode;
+
+use crate::{Error, Result, TemplateDatabase};
+
+// Virtual machine instructions between deadline checks, small enough to react within a few
+// milliseconds without slowing queries down noticeably.
+const PROGRESS_INTERVAL: i32 = 1000;
+
+impl TemplateDatabase {
+    // Runs `operation` and interrupts whatever statement is executing once `timeout` has
+    // elapsed, failing with `Error::Timeout`. An interrupted write transaction is rolled back.
+    pub fn with_timeout<T, F>(&mut self, timeout: Duration, operation: F) -> Result<T>
+    where
+        F: FnOnce(&mut TemplateDatabase) -> Result<T>,
+    {
+        let deadline = Instant::now() + timeout;
+        self.db
+            .progress_handler(PROGRESS_INTERVAL, Some(move || Instant::now() >= deadline));
+
+        let result = operation(self);
+
+        self.db.progress_handler(0, None::<fn() -> bool>);
+
+        result.map_err(|err| {
+            let code = err.sqlite().and_then(|x| x.sqlite_error_code());
+            match code {
+                Some(ErrorCode::OperationInterrupted) => Error::Timeout(timeout),
+                _ => err,
+            }
+        })
+    }
+}
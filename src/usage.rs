@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use rusqlite::{Connection, OptionalExtension, ToSql};
+
+use crate::error::with_context;
+use crate::{Error, Result, TemplateDatabase};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubUsage {
+    pub use_count: u64,
+    // Unix timestamps in seconds. `created_at` is unknown for substitutes stored before
+    // usage tracking existed.
+    pub last_used: Option<i64>,
+    pub created_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PruneReport {
+    pub pruned: Vec<String>,
+    pub remaining: usize,
+}
+
+impl TemplateDatabase {
+    // When enabled, every API that picks values at random counts the values it returns:
+    // `get_random_subs`, `get_random_picks`, `Template::random`, `TemplateSource::random_sub`,
+    // `CachedBackend`, `TemplateCache` and the `render` and `generate` families. Off by
+    // default, since it turns those reads into writes and makes a `TemplateCache` see the
+    // database as changed. The setting is stored in the database together with the time it
+    // was turned on, which `prune_unused` measures from. Turning it off forgets that time, so
+    // turning it back on starts counting afresh.
+    pub fn set_usage_tracking(&mut self, enabled: bool) -> Result<()> {
+        with_context("set_usage_tracking", None, None, || {
+            if enabled {
+                self.db.execute(
+                    "INSERT OR IGNORE INTO usage_tracking (id, started_at)
+                     VALUES (1, CAST(strftime('%s', 'now') AS INTEGER))",
+                    [],
+                )?;
+            } else {
+                self.db.execute("DELETE FROM usage_tracking", [])?;
+            }
+            self.track_usage = enabled;
+            Ok(())
+        })
+    }
+
+    pub(crate) fn execute_usage_tracked(db: &Connection) -> rusqlite::Result<bool> {
+        db.prepare_cached("SELECT 1 FROM usage_tracking")?
+            .exists([])
+    }
+
+    pub(crate) fn execute_record_use<T: ToSql>(
+        db: &Connection,
+        template_id: T,
+        substitute: &str,
+        count: u64,
+    ) -> rusqlite::Result<()> {
+        db.prepare_cached(
+            "UPDATE substitutes
+             SET use_count = use_count + ?3,
+                 last_used = CAST(strftime('%s', 'now') AS INTEGER)
+             WHERE template_id = ?1 AND name = ?2",
+        )?
+        .execute((template_id, substitute, count as i64))?;
+        Ok(())
+    }
+
+    pub fn get_sub_usage(
+        &self,
+        template: &str,
+        substitute: &str,
+    ) -> rusqlite::Result<Option<SubUsage>> {
+        self.db
+            .query_row(
+                "SELECT substitutes.use_count, substitutes.last_used, substitutes.created_at
+                 FROM substitutes
+                 JOIN templates ON templates.id = substitutes.template_id
+                 WHERE templates.name = ?1 AND substitutes.name = ?2",
+                [template, substitute],
+                |row| {
+                    Ok(SubUsage {
+                        use_count: row.get::<_, i64>(0)? as u64,
+                        last_used: row.get(1)?,
+                        created_at: row.get(2)?,
+                    })
+                },
+            )
+            .optional()
+    }
+
+    fn execute_prune_unused(
+        db: &Connection,
+        template: &str,
+        never_used_and_older_than: Duration,
+        dry_run: bool,
+    ) -> Result<PruneReport> {
+        let template_id: i64 = db.query_row(
+            "SELECT id FROM templates WHERE name = ?1",
+            [template],
+            |row| row.get(0),
+        )?;
+        let started_at: i64 = db
+            .query_row("SELECT started_at FROM usage_tracking", [], |row| {
+                row.get(0)
+            })
+            .optional()?
+            .ok_or(Error::UsageNotTracked)?;
+        let age = never_used_and_older_than.as_secs().min(i64::MAX as u64) as i64;
+
+        // A substitute has only gone unused since tracking started or since it was stored,
+        // whichever came last.
+        let condition = "template_id = ?1 AND use_count = 0
+             AND MAX(COALESCE(created_at, 0), ?3) <= CAST(strftime('%s', 'now') AS INTEGER) - ?2";
+
+        let pruned = {
+            let mut stmt = db.prepare(&format!(
+                "SELECT name FROM substitutes WHERE {} ORDER BY LOWER(name) ASC",
+                condition
+            ))?;
+            let pruned = stmt.query_map((template_id, age, started_at), |row| row.get(0))?;
+            pruned.collect::<rusqlite::Result<Vec<String>>>()?
+        };
+
+        if !dry_run {
+            db.execute(
+                &format!("DELETE FROM substitutes WHERE {}", condition),
+                (template_id, age, started_at),
+            )?;
+        }
+
+        let total: i64 = db.query_row(
+            "SELECT COUNT(*) FROM substitutes WHERE template_id = ?1",
+            [template_id],
+            |row| row.get(0),
+        )?;
+        let mut remaining = total as usize;
+        if dry_run {
+            remaining -= pruned.len();
+        }
+
+        Ok(PruneReport { pruned, remaining })
+    }
+
+    // Removes substitutes that went unselected for at least `never_used_and_older_than`,
+    // measured from when they were stored or when usage tracking was turned on, whichever is
+    // later. Fails with `Error::UsageNotTracked` unless tracking is on.
+    pub fn prune_unused(
+        &mut self,
+        template: &str,
+        never_used_and_older_than: Duration,
+    ) -> Result<PruneReport> {
+        with_context("prune_unused", Some(template), None, || {
+            let tx = self.db.savepoint()?;
+            Self::execute_check_unlocked(&tx, template)?;
+            let report =
+                Self::execute_prune_unused(&tx, template, never_used_and_older_than, false)?;
+            tx.commit()?;
+            Ok(report)
+        })
+    }
+
+    // Reports what `prune_unused` would remove without changing anything.
+    pub fn prune_unused_dry_run(
+        &self,
+        template: &str,
+        never_used_and_older_than: Duration,
+    ) -> Result<PruneReport> {
+        let tx = self.read_transaction()?;
+        let report = Self::execute_prune_unused(&tx, template, never_used_and_older_than, true)?;
+        tx.commit()?;
+        Ok(report)
+    }
+}
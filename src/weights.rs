@@ -0,0 +1,129 @@
+use rusqlite::{Connection, Rows, ToSql};
+
+use crate::rng::Rng;
+use crate::stats::word_count_sql;
+use crate::TemplateDatabase;
+
+// Substitutes with a weight of zero or less are disabled: every selection API skips them,
+// while listing APIs like `get_subs` still return them. Enabled substitutes are picked with
+// probability proportional to their weight.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct WeightedSubs {
+    pub(crate) subs: Vec<String>,
+    pub(crate) weights: Vec<i64>,
+}
+
+impl WeightedSubs {
+    pub(crate) fn load<T: ToSql>(
+        db: &Connection,
+        template_id: T,
+    ) -> rusqlite::Result<WeightedSubs> {
+        let mut stmt = db.prepare_cached(
+            "SELECT name, weight
+             FROM substitutes
+             WHERE template_id = ?1 AND weight > 0
+             ORDER BY LOWER(name) ASC",
+        )?;
+        let rows = stmt.query([template_id])?;
+        Self::read(rows)
+    }
+
+    pub(crate) fn load_with_word_count<T: ToSql>(
+        db: &Connection,
+        template_id: T,
+        words: usize,
+    ) -> rusqlite::Result<WeightedSubs> {
+        let mut stmt = db.prepare_cached(&format!(
+            "SELECT name, weight
+             FROM substitutes
+             WHERE template_id = ?1 AND weight > 0 AND {} = ?2
+             ORDER BY LOWER(name) ASC",
+            word_count_sql("name")
+        ))?;
+        let rows = stmt.query((template_id, words as i64))?;
+        Self::read(rows)
+    }
+
+    fn read(mut rows: Rows<'_>) -> rusqlite::Result<WeightedSubs> {
+        let mut loaded = WeightedSubs::default();
+        while let Some(row) = rows.next()? {
+            loaded.subs.push(row.get(0)?);
+            loaded.weights.push(row.get(1)?);
+        }
+        Ok(loaded)
+    }
+
+    pub(crate) fn pinned(value: &str) -> WeightedSubs {
+        WeightedSubs {
+            subs: vec![value.to_string()],
+            weights: vec![1],
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.subs.is_empty()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.subs.len()
+    }
+
+    pub(crate) fn pick(&self, rng: &mut Rng) -> Option<&str> {
+        rng.weighted_index(&self.weights)
+            .map(|index| self.subs[index].as_str())
+    }
+
+    // Draws up to `count` distinct substitutes, each draw weighted among those left.
+    pub(crate) fn pick_distinct(mut self, count: usize, rng: &mut Rng) -> Vec<String> {
+        let mut picked = Vec::with_capacity(count.min(self.subs.len()));
+        while picked.len() < count {
+            let Some(index) = rng.weighted_index(&self.weights) else {
+                break;
+            };
+            self.weights.swap_remove(index);
+            picked.push(self.subs.swap_remove(index));
+        }
+        picked
+    }
+}
+
+impl TemplateDatabase {
+    pub(crate) fn pick_weighted<T: ToSql>(
+        &self,
+        template_id: T,
+        rng: &mut Rng,
+    ) -> rusqlite::Result<String> {
+        let subs = WeightedSubs::load(&self.db, &template_id)?;
+        self.pick_from(subs, template_id, rng)
+    }
+
+    pub(crate) fn pick_from<T: ToSql>(
+        &self,
+        subs: WeightedSubs,
+        template_id: T,
+        rng: &mut Rng,
+    ) -> rusqlite::Result<String> {
+        let Some(sub) = subs.pick(rng) else {
+            return Ok(String::new());
+        };
+        if self.track_usage {
+            Self::execute_record_use(&self.db, &template_id, sub, 1)?;
+        }
+        Ok(sub.to_string())
+    }
+
+    // Substitutes that no selection API will return because their weight is zero or less.
+    pub fn get_disabled_subs(&self, template: &str) -> rusqlite::Result<Vec<String>> {
+        let template_id = self.find_template_id(template)?;
+        let mut stmt = self.db.prepare_cached(
+            "SELECT name
+             FROM substitutes
+             WHERE template_id = ?1 AND weight <= 0
+             ORDER BY LOWER(name) ASC",
+        )?;
+
+        let substitutes = stmt.query_map([template_id], |row| row.get(0))?;
+
+        substitutes.collect()
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Component, Path};
+
+use crate::error::with_context;
+use crate::{ConflictPolicy, ImportReport, Result, SubEntry, TemplateDatabase};
+
+const MANIFEST: &str = "manifest.tsv";
+
+// Lowercase ASCII letters, digits, `-` and `_` survive, everything else becomes `_`, so the
+// names are safe on case-insensitive file systems too.
+fn file_stem(template: &str) -> String {
+    let stem: String = template
+        .chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            c @ ('a'..='z' | '0'..='9' | '-' | '_') => c,
+            _ => '_',
+        })
+        .collect();
+    if stem.is_empty() {
+        return "_".to_string();
+    }
+    stem
+}
+
+fn read_manifest(dir: &Path) -> io::Result<Vec<(String, String)>> {
+    let file = match File::open(dir.join(MANIFEST)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if let Some((file, template)) = line.split_once('\t') {
+            entries.push((file.to_string(), template.to_string()));
+        }
+    }
+    Ok(entries)
+}
+
+// Manifest entries may only name a file directly inside the directory, never a path that
+// climbs out of it or starts at a root or drive.
+fn is_plain_file_name(file_name: &str) -> bool {
+    let mut components = Path::new(file_name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) && !file_name.contains(['/', '\\'])
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl TemplateDatabase {
+    // Writes each template to its own `<name>.txt` with one substitute per line, in the same
+    // order as `get_subs`, plus a `manifest.tsv` mapping file names back to template names.
+    // Files listed in an older manifest whose template is gone are removed, so re-exporting
+    // into the same directory yields a clean diff.
+    pub fn export_wordlists<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let tx = self.read_transaction()?;
+        let mut manifest = Vec::new();
+        let mut used = HashSet::new();
+
+        for template in self.get_templates()? {
+            if template.contains(['\t', '\n', '\r']) {
+                return Err(
+                    invalid_data(format!("template name {:?} spans fields", template)).into(),
+                );
+            }
+
+            let stem = file_stem(&template);
+            let mut file_name = format!("{}.txt", stem);
+            let mut suffix = 2;
+            while file_name == MANIFEST || !used.insert(file_name.clone()) {
+                file_name = format!("{}-{}.txt", stem, suffix);
+                suffix += 1;
+            }
+
+            let mut writer = BufWriter::new(File::create(dir.join(&file_name))?);
+            for sub in self.get_subs(&template)? {
+                if sub.contains(['\n', '\r']) {
+                    return Err(invalid_data(format!(
+                        "substitute {:?} in template '{}' contains a line break",
+                        sub, template
+                    ))
+                    .into());
+                }
+                writeln!(writer, "{}", sub)?;
+            }
+            writer.flush()?;
+
+            manifest.push((file_name, template));
+        }
+
+        tx.commit()?;
+
+        for (file_name, _) in read_manifest(dir)? {
+            if !used.contains(&file_name) && is_plain_file_name(&file_name) {
+                match fs::remove_file(dir.join(&file_name)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                    _ => {}
+                }
+            }
+        }
+
+        let mut writer = BufWriter::new(File::create(dir.join(MANIFEST))?);
+        for (file_name, template) in &manifest {
+            writeln!(writer, "{}\t{}", file_name, template)?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    // Reads a directory written by `export_wordlists` back in one transaction. Blank lines
+    // are skipped and existing substitutes are handled by `policy`, so importing with
+    // anything but `ConflictPolicy::Error` is idempotent.
+    pub fn import_wordlists<P: AsRef<Path>>(
+        &mut self,
+        dir: P,
+        policy: ConflictPolicy,
+    ) -> Result<ImportReport> {
+        with_context("import_wordlists", None, None, || {
+            let dir = dir.as_ref();
+            let manifest = read_manifest(dir)?;
+
+            let tx = self.db.savepoint()?;
+            let mut report = ImportReport::default();
+
+            for (file_name, template) in &manifest {
+                if !is_plain_file_name(file_name) {
+                    return Err(invalid_data(format!(
+                        "manifest entry {:?} is not a plain file name",
+                        file_name
+                    ))
+                    .into());
+                }
+                let mut lines = Vec::new();
+                for line in BufReader::new(File::open(dir.join(file_name))?).lines() {
+                    let line = line?;
+                    if !line.trim().is_empty() {
+                        lines.push(line);
+                    }
+                }
+                let entries: Vec<SubEntry> = lines.iter().map(|x| SubEntry::new(x)).collect();
+                Self::execute_import_entries(&tx, template, &entries, policy, &mut report)?;
+            }
+
+            tx.commit()?;
+
+            Ok(report)
+        })
+    }
+}